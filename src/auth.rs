@@ -6,6 +6,18 @@ use tracing::warn;
 
 use crate::{AppState, has_header_and_matches};
 
+#[inline]
+fn check_user_agent(parts: &Parts) -> Result<(), StatusCode> {
+    if has_header_and_matches(parts, "User-Agent", |value| {
+        !value.starts_with("Minecraft server")
+    }) {
+        warn!("invalid user agent in request");
+        return Err(StatusCode::IM_A_TEAPOT);
+    }
+
+    Ok(())
+}
+
 pub struct Authorized;
 
 impl FromRequestParts<AppState> for Authorized {
@@ -15,12 +27,7 @@ impl FromRequestParts<AppState> for Authorized {
         parts: &mut Parts,
         state: &AppState,
     ) -> Result<Self, Self::Rejection> {
-        if has_header_and_matches(parts, "User-Agent", |value| {
-            !value.starts_with("Minecraft server")
-        }) {
-            warn!("invalid user agent in request");
-            return Err(StatusCode::IM_A_TEAPOT);
-        }
+        check_user_agent(parts)?;
 
         if has_header_and_matches(parts, "Authorization", |value| {
             value != state.expected_auth_header.as_ref()
@@ -32,3 +39,25 @@ impl FromRequestParts<AppState> for Authorized {
         Ok(Authorized)
     }
 }
+
+/// Servers using the newer filter protocol authenticate with a token from
+/// Microsoft rather than our API key, which we have no way of checking, so
+/// only the user agent is, and it has to be there.
+pub struct ModernAuthorized;
+
+impl FromRequestParts<AppState> for ModernAuthorized {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if !parts.headers.contains_key("User-Agent") {
+            warn!("missing user agent in request");
+            return Err(StatusCode::IM_A_TEAPOT);
+        }
+
+        check_user_agent(parts)?;
+        Ok(ModernAuthorized)
+    }
+}
@@ -0,0 +1,434 @@
+use anyhow::{Result, anyhow};
+use serde::{Serialize, Serializer, ser::SerializeMap};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NamedColor {
+    Gray,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    Named(NamedColor),
+    /// An RGB color, which the server turns into the closest named color for
+    /// clients older than 1.16.
+    Hex(u32),
+}
+
+impl Serialize for Color {
+    #[inline]
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Named(named) => named.serialize(serializer),
+            Self::Hex(rgb) => serializer.serialize_str(&format!("#{:06X}", rgb & 0xFF_FFFF)),
+        }
+    }
+}
+
+impl From<NamedColor> for Color {
+    #[inline]
+    fn from(value: NamedColor) -> Self {
+        Self::Named(value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HoverEvent {
+    ShowText(Box<Component>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClickEvent {
+    OpenUrl(String),
+    SuggestCommand(String),
+}
+
+/// A JSON text component, as taken by `tellraw`. Build one up with the
+/// methods below rather than formatting JSON by hand.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Component {
+    pub text: String,
+    pub color: Option<Color>,
+    pub bold: Option<bool>,
+    pub italic: Option<bool>,
+    pub underlined: Option<bool>,
+    pub strikethrough: Option<bool>,
+    pub obfuscated: Option<bool>,
+    pub hover_event: Option<HoverEvent>,
+    pub click_event: Option<ClickEvent>,
+    pub extra: Vec<Component>,
+}
+
+impl Component {
+    #[inline]
+    pub fn text(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            ..Default::default()
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn color(mut self, color: impl Into<Color>) -> Self {
+        self.color = Some(color.into());
+        self
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn bold(mut self) -> Self {
+        self.bold = Some(true);
+        self
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn italic(mut self) -> Self {
+        self.italic = Some(true);
+        self
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn underlined(mut self) -> Self {
+        self.underlined = Some(true);
+        self
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn strikethrough(mut self) -> Self {
+        self.strikethrough = Some(true);
+        self
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn obfuscated(mut self) -> Self {
+        self.obfuscated = Some(true);
+        self
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn hover_text(mut self, text: Self) -> Self {
+        self.hover_event = Some(HoverEvent::ShowText(Box::new(text)));
+        self
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn click(mut self, event: ClickEvent) -> Self {
+        self.click_event = Some(event);
+        self
+    }
+
+    /// Adds a child, which inherits this component's formatting.
+    #[inline]
+    #[must_use]
+    pub fn child(mut self, child: Self) -> Self {
+        self.extra.push(child);
+        self
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn children(mut self, children: impl IntoIterator<Item = Self>) -> Self {
+        self.extra.extend(children);
+        self
+    }
+}
+
+/// How hover and click events are written, which changed in 1.21.5. Each
+/// version ignores the other's keys, so only one of them is ever written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EventFormat {
+    /// `hoverEvent` and `clickEvent`, from before 1.21.5.
+    Legacy,
+    /// `hover_event` and `click_event`, from 1.21.5 on.
+    #[default]
+    Modern,
+}
+
+impl EventFormat {
+    /// The format a server on a release `version` like `1.21.4` expects.
+    #[inline]
+    pub fn for_version(version: &str) -> Result<Self> {
+        let parts = version
+            .trim()
+            .split('.')
+            .map(str::parse)
+            .collect::<Result<Vec<u32>, _>>()
+            .ok()
+            .filter(|parts| (2..=3).contains(&parts.len()))
+            .ok_or_else(|| anyhow!("expected a release version like 1.21.4, got {version:?}"))?;
+
+        let version = (parts[0], parts[1], parts.get(2).copied().unwrap_or(0));
+
+        Ok(if version >= (1, 21, 5) {
+            Self::Modern
+        } else {
+            Self::Legacy
+        })
+    }
+}
+
+/// Turns components into commands for the server.
+#[derive(Debug, Clone)]
+pub struct Tellraw {
+    pub prefix: String,
+    pub format: EventFormat,
+    /// The longest command the server takes, if there's a limit.
+    pub max_length: Option<usize>,
+}
+
+impl Tellraw {
+    /// The full `tellraw` command to show `component`.
+    #[inline]
+    pub fn command(&self, component: &Component) -> String {
+        format!("{} {}", self.prefix, self.json(component))
+    }
+
+    #[inline]
+    fn json(&self, component: &Component) -> String {
+        serde_json::to_string(&Formatted {
+            component,
+            format: self.format,
+        })
+        .expect("components always serialize")
+    }
+}
+
+struct Formatted<'a> {
+    component: &'a Component,
+    format: EventFormat,
+}
+
+impl Formatted<'_> {
+    #[inline]
+    const fn with<'b>(&self, component: &'b Component) -> Formatted<'b> {
+        Formatted {
+            component,
+            format: self.format,
+        }
+    }
+}
+
+struct FormattedHoverEvent<'a> {
+    event: &'a HoverEvent,
+    format: EventFormat,
+}
+
+impl Serialize for FormattedHoverEvent<'_> {
+    #[inline]
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(2))?;
+
+        match self.event {
+            HoverEvent::ShowText(text) => {
+                let key = match self.format {
+                    EventFormat::Legacy => "contents",
+                    EventFormat::Modern => "value",
+                };
+
+                map.serialize_entry("action", "show_text")?;
+                map.serialize_entry(
+                    key,
+                    &Formatted {
+                        component: text,
+                        format: self.format,
+                    },
+                )?;
+            }
+        }
+
+        map.end()
+    }
+}
+
+struct FormattedClickEvent<'a> {
+    event: &'a ClickEvent,
+    format: EventFormat,
+}
+
+impl Serialize for FormattedClickEvent<'_> {
+    #[inline]
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (action, modern_key, value) = match self.event {
+            ClickEvent::OpenUrl(url) => ("open_url", "url", url),
+            ClickEvent::SuggestCommand(command) => ("suggest_command", "command", command),
+        };
+
+        let key = match self.format {
+            EventFormat::Legacy => "value",
+            EventFormat::Modern => modern_key,
+        };
+
+        let mut map = serializer.serialize_map(Some(2))?;
+        map.serialize_entry("action", action)?;
+        map.serialize_entry(key, value)?;
+        map.end()
+    }
+}
+
+impl Serialize for Formatted<'_> {
+    #[inline]
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let component = self.component;
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("text", &component.text)?;
+
+        if let Some(color) = &component.color {
+            map.serialize_entry("color", color)?;
+        }
+
+        let flags = [
+            ("bold", component.bold),
+            ("italic", component.italic),
+            ("underlined", component.underlined),
+            ("strikethrough", component.strikethrough),
+            ("obfuscated", component.obfuscated),
+        ];
+
+        for (key, flag) in flags {
+            if let Some(flag) = flag {
+                map.serialize_entry(key, &flag)?;
+            }
+        }
+
+        let (hover_key, click_key) = match self.format {
+            EventFormat::Legacy => ("hoverEvent", "clickEvent"),
+            EventFormat::Modern => ("hover_event", "click_event"),
+        };
+
+        if let Some(event) = &component.hover_event {
+            map.serialize_entry(
+                hover_key,
+                &FormattedHoverEvent {
+                    event,
+                    format: self.format,
+                },
+            )?;
+        }
+
+        if let Some(event) = &component.click_event {
+            map.serialize_entry(
+                click_key,
+                &FormattedClickEvent {
+                    event,
+                    format: self.format,
+                },
+            )?;
+        }
+
+        if !component.extra.is_empty() {
+            let extra: Vec<_> = component
+                .extra
+                .iter()
+                .map(|child| self.with(child))
+                .collect();
+            map.serialize_entry("extra", &extra)?;
+        }
+
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tellraw(format: EventFormat) -> Tellraw {
+        Tellraw {
+            prefix: "tellraw @a".to_string(),
+            format,
+            max_length: None,
+        }
+    }
+
+    fn mention() -> Component {
+        Component::text("<Steve>")
+            .color(Color::Hex(0x1ABC9C))
+            .hover_text(
+                Component::text("@steve").child(Component::text("\nID: 1").color(NamedColor::Gray)),
+            )
+            .click(ClickEvent::SuggestCommand("@steve ".to_string()))
+    }
+
+    #[test]
+    fn escapes_text() {
+        let component = Component::text(r#"say "hi" \ bye"#).child(Component::text("line\nbreak"));
+
+        assert_eq!(
+            tellraw(EventFormat::Modern).command(&component),
+            r#"tellraw @a {"text":"say \"hi\" \\ bye","extra":[{"text":"line\nbreak"}]}"#
+        );
+    }
+
+    #[test]
+    fn writes_styles() {
+        let component = Component::text("x")
+            .color(NamedColor::Gray)
+            .bold()
+            .italic()
+            .underlined()
+            .strikethrough()
+            .obfuscated();
+
+        assert_eq!(
+            tellraw(EventFormat::Modern).json(&component),
+            r#"{"text":"x","color":"gray","bold":true,"italic":true,"underlined":true,"strikethrough":true,"obfuscated":true}"#
+        );
+    }
+
+    #[test]
+    fn writes_legacy_events() {
+        assert_eq!(
+            tellraw(EventFormat::Legacy).json(&mention()),
+            r##"{"text":"<Steve>","color":"#1ABC9C","hoverEvent":{"action":"show_text","contents":{"text":"@steve","extra":[{"text":"\nID: 1","color":"gray"}]}},"clickEvent":{"action":"suggest_command","value":"@steve "}}"##
+        );
+
+        let link = Component::text("x").click(ClickEvent::OpenUrl("https://a.b/\"".to_string()));
+        assert_eq!(
+            tellraw(EventFormat::Legacy).json(&link),
+            r#"{"text":"x","clickEvent":{"action":"open_url","value":"https://a.b/\""}}"#
+        );
+    }
+
+    #[test]
+    fn writes_modern_events() {
+        assert_eq!(
+            tellraw(EventFormat::Modern).json(&mention()),
+            r##"{"text":"<Steve>","color":"#1ABC9C","hover_event":{"action":"show_text","value":{"text":"@steve","extra":[{"text":"\nID: 1","color":"gray"}]}},"click_event":{"action":"suggest_command","command":"@steve "}}"##
+        );
+
+        let link = Component::text("x").click(ClickEvent::OpenUrl("https://a.b/\"".to_string()));
+        assert_eq!(
+            tellraw(EventFormat::Modern).json(&link),
+            r#"{"text":"x","click_event":{"action":"open_url","url":"https://a.b/\""}}"#
+        );
+    }
+
+    #[test]
+    fn picks_format_from_version() {
+        for (version, format) in [
+            ("1.20.1", EventFormat::Legacy),
+            ("1.21", EventFormat::Legacy),
+            ("1.21.4", EventFormat::Legacy),
+            ("1.21.5", EventFormat::Modern),
+            ("1.21.10", EventFormat::Modern),
+            (" 1.22 ", EventFormat::Modern),
+        ] {
+            assert_eq!(
+                EventFormat::for_version(version).unwrap(),
+                format,
+                "{version}"
+            );
+        }
+
+        for version in ["", "1", "25w14a", "1.21.5-pre1", "1.2.3.4"] {
+            assert!(EventFormat::for_version(version).is_err(), "{version}");
+        }
+    }
+}
@@ -0,0 +1,511 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{OpenOptions, read_to_string},
+    io::AsyncWriteExt,
+    sync::{broadcast, mpsc::UnboundedSender, oneshot},
+    time::{Instant, timeout, timeout_at},
+};
+use tracing::{info, warn};
+use twilight_model::{
+    gateway::payload::incoming::MessageCreate,
+    id::{
+        Id,
+        marker::{RoleMarker, UserMarker},
+    },
+};
+
+use crate::{
+    AppState,
+    content::strip_minecraft_formatting,
+    events::strip_log_prefix,
+    rcon::{MAX_COMMAND_LENGTH, RconClient},
+};
+
+const EXECUTE_TIMEOUT: Duration = Duration::from_secs(30);
+/// The server doesn't tell us when it's done responding to a command typed
+/// into its console, so we collect whatever it logs until it goes quiet.
+const OUTPUT_QUIET_PERIOD: Duration = Duration::from_millis(250);
+const MAX_OUTPUT_WAIT: Duration = Duration::from_secs(2);
+/// Discord's message length limit, minus room for the code block.
+const MAX_REPLY_LENGTH: usize = 2000 - 8;
+
+#[derive(Debug)]
+pub struct ConsoleCommand {
+    pub command: String,
+    pub reply: oneshot::Sender<String>,
+}
+
+impl ConsoleCommand {
+    /// Commands that sat waiting for a server until whoever sent them gave
+    /// up, which they'd have been told failed, so they shouldn't run late.
+    #[inline]
+    pub fn is_abandoned(&self) -> bool {
+        self.reply.is_closed()
+    }
+}
+
+/// Somewhere we can run commands: the wrapped server's stdin, or RCON.
+#[derive(Debug, Clone)]
+pub enum Console {
+    Wrapper(UnboundedSender<ConsoleCommand>),
+    Rcon(RconClient),
+}
+
+impl Console {
+    #[inline]
+    pub async fn execute(&self, command: impl Into<String>) -> Result<String> {
+        match self {
+            Self::Wrapper(command_sender) => {
+                let (reply, response) = oneshot::channel();
+
+                command_sender.send(ConsoleCommand {
+                    command: command.into(),
+                    reply,
+                })?;
+
+                timeout(EXECUTE_TIMEOUT, response)
+                    .await
+                    .map_err(|_| anyhow!("timed out waiting for the server"))?
+                    .map_err(|_| anyhow!("server went away before the command finished"))
+            }
+            Self::Rcon(rcon_client) => rcon_client.execute(command).await,
+        }
+    }
+
+    /// The longest command this console takes, if there's a limit.
+    #[inline]
+    pub const fn max_command_length(&self) -> Option<usize> {
+        match self {
+            Self::Wrapper(_) => None,
+            Self::Rcon(_) => Some(MAX_COMMAND_LENGTH),
+        }
+    }
+}
+
+/// Gathers what the server logs after a command was written to its stdin.
+/// Anything else the server happens to log at the same time is included too.
+#[inline]
+pub async fn collect_output(mut output_receiver: broadcast::Receiver<String>) -> String {
+    let deadline = Instant::now() + MAX_OUTPUT_WAIT;
+    let mut output = Vec::new();
+
+    loop {
+        let quiet_deadline = (Instant::now() + OUTPUT_QUIET_PERIOD).min(deadline);
+
+        match timeout_at(quiet_deadline, output_receiver.recv()).await {
+            Ok(Ok(line)) => output.push(strip_log_prefix(&line).to_string()),
+            Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+            Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => break,
+        }
+    }
+
+    output.join("\n")
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RolePermissions {
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+}
+
+/// Which Discord roles may run which commands. Each entry is matched against
+/// the start of a command by whole words, so `whitelist` covers
+/// `whitelist add Foo`, and `*` covers everything. A deny from any of a
+/// member's roles wins over an allow from another.
+#[derive(Debug, Default, Deserialize)]
+pub struct ConsolePermissions {
+    #[serde(default)]
+    roles: HashMap<u64, RolePermissions>,
+}
+
+#[inline]
+fn matches_command(pattern: &str, command: &str) -> bool {
+    let pattern = pattern.trim().trim_start_matches('/');
+    let pattern = pattern.strip_prefix("minecraft:").unwrap_or(pattern);
+    let command = command.strip_prefix("minecraft:").unwrap_or(command);
+
+    pattern == "*"
+        || command
+            .strip_prefix(pattern)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+}
+
+impl ConsolePermissions {
+    #[inline]
+    pub async fn load(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_str(&read_to_string(path).await?)?)
+    }
+
+    /// Whether a single command is allowed, without looking inside it.
+    #[inline]
+    fn allows(&self, roles: &[Id<RoleMarker>], command: &str) -> bool {
+        let role_permissions = roles
+            .iter()
+            .filter_map(|role| self.roles.get(&role.get()))
+            .collect::<Vec<_>>();
+
+        let denied = role_permissions
+            .iter()
+            .flat_map(|permissions| &permissions.deny)
+            .any(|pattern| matches_command(pattern, command));
+
+        !denied
+            && role_permissions
+                .iter()
+                .flat_map(|permissions| &permissions.allow)
+                .any(|pattern| matches_command(pattern, command))
+    }
+
+    /// Whether `command` is allowed, along with everything an `execute` or
+    /// `return` in it runs, so a denied command can't be wrapped in an
+    /// allowed one.
+    #[inline]
+    pub fn is_allowed(&self, roles: &[Id<RoleMarker>], command: &str) -> bool {
+        // a line break would end the command early and start another one on
+        // the server console
+        if command.contains(char::is_control) {
+            return false;
+        }
+
+        let command = command.trim();
+
+        if !self.allows(roles, command) {
+            return false;
+        }
+
+        if !matches_command("execute", command) && !matches_command("return", command) {
+            return true;
+        }
+
+        // we can't tell where the server would see a `run`, so better not to
+        // guess
+        let Some(words) = words(command) else {
+            return false;
+        };
+
+        // every `run` is checked rather than just the first, since an
+        // argument can be called `run` too
+        words.into_iter().all(|(start, word)| match word {
+            "run" => self.allows(roles, command[start + word.len()..].trim()),
+            // `execute if function` runs the function to get its result
+            "function" => self.allows(roles, &command[start..]),
+            _ => true,
+        })
+    }
+}
+
+/// Splits a command into words along with where they start, the way the
+/// server reads them, so quoted strings and anything in brackets stay
+/// together. `None` if a quote or bracket is never closed.
+#[inline]
+fn words(command: &str) -> Option<Vec<(usize, &str)>> {
+    let mut words = Vec::new();
+    let mut word_start = None;
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut escaped = false;
+
+    for (index, c) in command.char_indices() {
+        if let Some(open) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == open {
+                quote = None;
+            }
+
+            continue;
+        }
+
+        if c == ' ' && depth == 0 {
+            if let Some(start) = word_start.take() {
+                words.push((start, &command[start..index]));
+            }
+
+            continue;
+        }
+
+        // the server only reads a quoted string at the start of an argument,
+        // but anything in brackets can be one
+        if matches!(c, '"' | '\'') && (word_start.is_none() || depth > 0) {
+            quote = Some(c);
+        }
+
+        match c {
+            '[' | '{' | '(' => depth += 1,
+            ']' | '}' | ')' => depth = depth.checked_sub(1)?,
+            _ => {}
+        }
+
+        word_start.get_or_insert(index);
+    }
+
+    if quote.is_some() || depth > 0 {
+        return None;
+    }
+
+    if let Some(start) = word_start {
+        words.push((start, &command[start..]));
+    }
+
+    Some(words)
+}
+
+#[derive(Debug, Serialize)]
+struct AuditEntry<'a> {
+    timestamp: u64,
+    user_id: Id<UserMarker>,
+    username: &'a str,
+    command: &'a str,
+    outcome: &'a str,
+}
+
+#[inline]
+async fn audit(
+    state: &AppState,
+    user_id: Id<UserMarker>,
+    username: &str,
+    command: &str,
+    outcome: &str,
+) {
+    info!(target: "audit", %user_id, username, command, outcome, "console command");
+
+    let Some(path) = state.console_audit_log.as_deref() else {
+        return;
+    };
+
+    let entry = AuditEntry {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        user_id,
+        username,
+        command,
+        outcome,
+    };
+
+    let res = async {
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?
+            .write_all(&line)
+            .await?;
+
+        anyhow::Ok(())
+    }
+    .await;
+
+    if let Err(e) = res {
+        warn!(?e, "failed to write console audit log");
+    }
+}
+
+#[inline]
+fn format_reply(output: &str) -> String {
+    let output = strip_minecraft_formatting(output).replace("```", "`\u{200b}``");
+
+    if output.trim().is_empty() {
+        return "Command ran with no output".to_string();
+    }
+
+    let mut end = output.len().min(MAX_REPLY_LENGTH);
+
+    while !output.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    format!("```\n{}```", &output[..end])
+}
+
+/// Runs a command on behalf of a Discord member, if their roles allow it, and
+/// returns what should be sent back to them.
+#[inline]
+pub async fn run_as_member(
+    state: &AppState,
+    user_id: Id<UserMarker>,
+    username: &str,
+    roles: &[Id<RoleMarker>],
+    command: &str,
+) -> Result<String> {
+    let command = command.trim().trim_start_matches('/');
+
+    let Some(permissions) = &state.console_permissions else {
+        bail!("console commands aren't set up");
+    };
+
+    if command.is_empty() {
+        return Ok("Specify a command to run".to_string());
+    }
+
+    if !permissions.is_allowed(roles, command) {
+        audit(state, user_id, username, command, "denied").await;
+        return Ok("You don't have permission to run that command".to_string());
+    }
+
+    if let Some(max_length) = state.console.max_command_length()
+        && command.len() > max_length
+    {
+        audit(state, user_id, username, command, "too long").await;
+        return Ok(format!(
+            "That command is too long, the server only takes up to {max_length} bytes over RCON"
+        ));
+    }
+
+    match state.console.execute(command).await {
+        Ok(output) => {
+            audit(state, user_id, username, command, "ran").await;
+            Ok(format_reply(&output))
+        }
+        Err(e) => {
+            audit(state, user_id, username, command, "failed").await;
+            Err(e)
+        }
+    }
+}
+
+#[inline]
+pub async fn handle_console_message(state: AppState, message: Box<MessageCreate>) {
+    let command = message
+        .content
+        .strip_prefix(state.console_prefix.as_ref())
+        .unwrap_or_default();
+
+    let roles = message
+        .member
+        .as_ref()
+        .map(|member| member.roles.as_slice())
+        .unwrap_or_default();
+
+    let reply = match run_as_member(
+        &state,
+        message.author.id,
+        &message.author.name,
+        roles,
+        command,
+    )
+    .await
+    {
+        Ok(reply) => reply,
+        Err(e) => {
+            warn!(?e, "failed to run console command");
+            "Failed to run the command, check the bridge logs".to_string()
+        }
+    };
+
+    if let Err(e) = state
+        .client
+        .create_message(message.channel_id)
+        .reply(message.id)
+        .content(&reply)
+        .await
+    {
+        warn!(?e, "failed to reply to console command");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODERATOR: Id<RoleMarker> = Id::new(1);
+    const ADMIN: Id<RoleMarker> = Id::new(2);
+
+    fn permissions() -> ConsolePermissions {
+        serde_json::from_str(
+            r#"{
+                "roles": {
+                    "1": { "allow": ["say", "whitelist", "execute"], "deny": ["whitelist remove", "op"] },
+                    "2": { "allow": ["*"] }
+                }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn matches_whole_words() {
+        let permissions = permissions();
+
+        assert!(permissions.is_allowed(&[MODERATOR], "say hi"));
+        assert!(permissions.is_allowed(&[MODERATOR], "whitelist add Steve"));
+        assert!(!permissions.is_allowed(&[MODERATOR], "sayhi"));
+        assert!(!permissions.is_allowed(&[MODERATOR], "whitelist remove Steve"));
+        assert!(!permissions.is_allowed(&[MODERATOR], "stop"));
+        assert!(!permissions.is_allowed(&[], "say hi"));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let permissions = permissions();
+
+        assert!(permissions.is_allowed(&[ADMIN], "whitelist remove Steve"));
+        assert!(!permissions.is_allowed(&[MODERATOR, ADMIN], "whitelist remove Steve"));
+    }
+
+    #[test]
+    fn rejects_control_characters() {
+        let permissions = permissions();
+
+        assert!(!permissions.is_allowed(&[MODERATOR], "say hi\nop Me"));
+        assert!(!permissions.is_allowed(&[MODERATOR], "say hi\rop Me"));
+        assert!(!permissions.is_allowed(&[MODERATOR], "say hi\u{0}op Me"));
+        assert!(!permissions.is_allowed(&[ADMIN], "say hi\nop Me"));
+    }
+
+    #[test]
+    fn checks_commands_run_by_execute() {
+        let permissions = permissions();
+
+        assert!(permissions.is_allowed(&[MODERATOR], "execute as @a run say hi"));
+        assert!(!permissions.is_allowed(&[MODERATOR], "execute as @a run op Me"));
+        assert!(!permissions.is_allowed(&[MODERATOR], "execute as @a run execute at @s run op Me"));
+        assert!(!permissions.is_allowed(&[MODERATOR], "execute run stop"));
+        assert!(permissions.is_allowed(&[ADMIN], "execute run stop"));
+        assert!(!permissions.is_allowed(&[MODERATOR], "return run op Me"));
+
+        assert!(!permissions.is_allowed(
+            &[MODERATOR],
+            r#"execute as @a[name=" run say x"] run op Me"#
+        ));
+        assert!(!permissions.is_allowed(
+            &[MODERATOR],
+            r#"execute as @a[name=" \" run say x"] run op Me"#
+        ));
+        assert!(!permissions.is_allowed(&[MODERATOR], "execute as run run op Me"));
+        assert!(!permissions.is_allowed(&[MODERATOR, ADMIN], "execute as run run op Me"));
+        assert!(!permissions.is_allowed(&[MODERATOR], r#"execute as @a[name="x] run op Me"#));
+        assert!(permissions.is_allowed(
+            &[MODERATOR],
+            r#"execute as @a[name=" run op Me"] run say hi"#
+        ));
+
+        assert!(!permissions.is_allowed(&[MODERATOR], "execute if function ns:fn"));
+        assert!(!permissions.is_allowed(&[MODERATOR], "execute unless function ns:fn run say hi"));
+        assert!(permissions.is_allowed(&[ADMIN], "execute if function ns:fn"));
+    }
+
+    #[test]
+    fn ignores_namespace() {
+        let permissions = permissions();
+
+        assert!(!permissions.is_allowed(&[MODERATOR], "minecraft:op Me"));
+        assert!(!permissions.is_allowed(&[MODERATOR], "minecraft:execute run op Me"));
+        assert!(permissions.is_allowed(&[MODERATOR], "minecraft:say hi"));
+    }
+}
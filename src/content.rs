@@ -1,4 +1,252 @@
+use std::borrow::Cow;
+
 #[inline]
 pub fn escape_minecraft(inp: &str) -> String {
     inp.replace("\u{00a7}", "&")
 }
+
+/// Removes `§` formatting codes, along with the character following each one.
+#[inline]
+pub fn strip_minecraft_formatting(inp: &str) -> String {
+    let mut output = String::with_capacity(inp.len());
+    let mut chars = inp.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\u{00a7}' {
+            chars.next();
+        } else {
+            output.push(c);
+        }
+    }
+
+    output
+}
+
+/// The Discord markdown for a formatting code, or `None` for colors and
+/// resets, which also turn off any formatting before them.
+#[inline]
+fn markdown_for(code: char) -> Option<&'static str> {
+    match code.to_ascii_lowercase() {
+        'l' => Some("**"),
+        'o' => Some("*"),
+        'n' => Some("__"),
+        'm' => Some("~~"),
+        'k' => Some("||"),
+        _ => None,
+    }
+}
+
+/// Closes everything in `open`, leaving any trailing whitespace outside the
+/// markers.
+#[inline]
+fn close_markdown(output: &mut String, open: &mut Vec<&str>) {
+    if open.is_empty() {
+        return;
+    }
+
+    let trailing = output.split_off(output.trim_end().len());
+
+    while let Some(marker) = open.pop() {
+        output.push_str(marker);
+    }
+
+    output.push_str(&trailing);
+}
+
+/// Writes `text` after opening whatever formatting is waiting to start. It
+/// waits until there's something besides whitespace, since Discord won't
+/// format text that starts with a space either.
+#[inline]
+fn push_markdown_text<'m>(
+    output: &mut String,
+    open: &mut Vec<&'m str>,
+    pending: &mut Vec<&'m str>,
+    text: &str,
+    escape: impl for<'a> Fn(&'a str) -> Cow<'a, str>,
+) {
+    let trimmed = text.trim_start();
+
+    if trimmed.is_empty() {
+        output.push_str(text);
+        return;
+    }
+
+    output.push_str(&text[..text.len() - trimmed.len()]);
+
+    for marker in pending.drain(..) {
+        output.push_str(marker);
+        open.push(marker);
+    }
+
+    output.push_str(&escape(trimmed));
+}
+
+/// Turns `§` formatting codes into the closest Discord markdown, escaping
+/// the text between them with `escape`. Colors have no equivalent, so they
+/// just end whatever formatting came before them, the same as in game.
+#[inline]
+pub fn minecraft_to_markdown(
+    inp: &str,
+    escape: impl for<'a> Fn(&'a str) -> Cow<'a, str>,
+) -> String {
+    let mut output = String::with_capacity(inp.len());
+    let mut open = Vec::new();
+    let mut pending = Vec::new();
+    let mut parts = inp.split('\u{00a7}');
+
+    if let Some(text) = parts.next() {
+        push_markdown_text(&mut output, &mut open, &mut pending, text, &escape);
+    }
+
+    for part in parts {
+        let mut chars = part.chars();
+
+        // a `§` right at the end doesn't do anything
+        let Some(code) = chars.next() else {
+            continue;
+        };
+
+        match markdown_for(code) {
+            Some(marker) => {
+                if !open.contains(&marker) && !pending.contains(&marker) {
+                    pending.push(marker);
+                }
+            }
+            None => {
+                close_markdown(&mut output, &mut open);
+                pending.clear();
+            }
+        }
+
+        push_markdown_text(
+            &mut output,
+            &mut open,
+            &mut pending,
+            chars.as_str(),
+            &escape,
+        );
+    }
+
+    close_markdown(&mut output, &mut open);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline]
+    fn to_markdown(inp: &str) -> String {
+        minecraft_to_markdown(inp, |text| Cow::Borrowed(text))
+    }
+
+    #[test]
+    fn escapes_section_signs() {
+        assert_eq!(escape_minecraft("§aHello §lthere"), "&aHello &lthere");
+    }
+
+    #[test]
+    fn strips_formatting() {
+        assert_eq!(
+            strip_minecraft_formatting("§a§lHello§r there§"),
+            "Hello there"
+        );
+    }
+
+    #[test]
+    fn leaves_plain_text_alone() {
+        assert_eq!(to_markdown(""), "");
+        assert_eq!(to_markdown("Hello there"), "Hello there");
+    }
+
+    #[test]
+    fn converts_each_format() {
+        assert_eq!(to_markdown("§lbold"), "**bold**");
+        assert_eq!(to_markdown("§oitalic"), "*italic*");
+        assert_eq!(to_markdown("§nunderline"), "__underline__");
+        assert_eq!(to_markdown("§mstrike"), "~~strike~~");
+        assert_eq!(to_markdown("§khidden"), "||hidden||");
+    }
+
+    #[test]
+    fn codes_are_case_insensitive() {
+        assert_eq!(to_markdown("§Lbold§RHello"), "**bold**Hello");
+    }
+
+    #[test]
+    fn drops_colors() {
+        assert_eq!(to_markdown("§cred §9blue §fwhite"), "red blue white");
+    }
+
+    #[test]
+    fn drops_hex_colors() {
+        assert_eq!(to_markdown("§x§f§f§8§8§0§0orange"), "orange");
+        assert_eq!(
+            to_markdown("§x§F§F§8§8§0§0§lbold orange"),
+            "**bold orange**"
+        );
+    }
+
+    #[test]
+    fn nests_formats() {
+        assert_eq!(to_markdown("§lbold §oand italic"), "**bold *and italic***");
+        assert_eq!(to_markdown("§l§nboth"), "**__both__**");
+    }
+
+    #[test]
+    fn colors_reset_formatting() {
+        assert_eq!(to_markdown("§lbold§cred"), "**bold**red");
+        assert_eq!(to_markdown("§l§cred"), "red");
+    }
+
+    #[test]
+    fn resets_formatting() {
+        assert_eq!(to_markdown("§l§oboth§r plain"), "***both*** plain");
+    }
+
+    #[test]
+    fn handles_formatting_before_colors() {
+        // the color comes after, so it turns the bold right back off
+        assert_eq!(to_markdown("&l§l§c&cHello"), "&l&cHello");
+        assert_eq!(to_markdown("§c§lHello"), "**Hello**");
+    }
+
+    #[test]
+    fn ignores_repeated_codes() {
+        assert_eq!(to_markdown("§l§lbold§l still"), "**bold still**");
+    }
+
+    #[test]
+    fn keeps_whitespace_outside_markers() {
+        assert_eq!(to_markdown("§l  bold  §rplain"), "  **bold**  plain");
+        assert_eq!(to_markdown("a §l b §r c"), "a  **b**  c");
+    }
+
+    #[test]
+    fn skips_empty_formatting() {
+        assert_eq!(to_markdown("§l§r"), "");
+        assert_eq!(to_markdown("§l   "), "   ");
+        assert_eq!(to_markdown("Hello§l"), "Hello");
+    }
+
+    #[test]
+    fn drops_unknown_and_trailing_codes() {
+        assert_eq!(to_markdown("§zHello§"), "Hello");
+        assert_eq!(to_markdown("§"), "");
+        assert_eq!(to_markdown("§§lbold"), "**bold**");
+    }
+
+    #[test]
+    fn handles_multibyte_codes() {
+        assert_eq!(to_markdown("§éHello §lwörld"), "Hello **wörld**");
+    }
+
+    #[test]
+    fn escapes_text_between_codes() {
+        let escaped = minecraft_to_markdown("2*3 is §l*six*", |text| {
+            Cow::Owned(text.replace('*', "\\*"))
+        });
+
+        assert_eq!(escaped, "2\\*3 is **\\*six\\***");
+    }
+}
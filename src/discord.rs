@@ -1,144 +1,719 @@
 use anyhow::Result;
-use regex::Regex;
-use std::{borrow::Cow, sync::Arc};
-use tokio::{spawn, sync::mpsc::UnboundedSender};
+use std::{borrow::Cow, os::unix::process::ExitStatusExt, process::ExitStatus, slice};
+use tokio::{
+    select, spawn,
+    sync::{
+        mpsc::{UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+};
 use twilight_gateway::{Event, EventTypeFlags, Intents, Shard, ShardId, StreamExt};
 use uuid::{Uuid, fmt::Simple};
 
-use tracing::warn;
-use twilight_http::Client;
-use twilight_model::id::{
-    Id,
-    marker::{ChannelMarker, WebhookMarker},
+use tracing::{info, warn};
+use twilight_model::{
+    application::{
+        command::{Command, CommandOption, CommandOptionType, CommandType},
+        interaction::{
+            Interaction, InteractionContextType, InteractionData,
+            application_command::{CommandData, CommandOptionValue},
+        },
+    },
+    channel::message::{Embed, embed::EmbedAuthor},
+    gateway::payload::incoming::{GuildCreate, InteractionCreate},
+    http::interaction::{InteractionResponse, InteractionResponseType},
+    id::{
+        Id,
+        marker::{ApplicationMarker, ChannelMarker, UserMarker},
+    },
+};
+
+use crate::{
+    AppState,
+    component::{ClickEvent, Color, Component, NamedColor, Tellraw},
+    console::{handle_console_message, run_as_member},
+    content::{escape_minecraft, minecraft_to_markdown},
+    events::{AdvancementKind, GameEvent},
+    markdown,
+    outbound::OutboundMessage,
+    ping::{describe_status, ping},
+    players::{describe_player_list, list_players},
+    presence::{Presence, refresh_max_players, refresh_player_count, set_server_online},
+    query::query,
 };
 
-use crate::{AppState, content::escape_minecraft};
+const TASK_COLOR: u32 = 0x55FF55;
+const CHALLENGE_COLOR: u32 = 0xAA00AA;
+
+#[derive(Debug)]
+pub enum WebhookBody {
+    Text(String),
+    Embed(Box<Embed>),
+}
+
+impl From<String> for WebhookBody {
+    #[inline]
+    fn from(value: String) -> Self {
+        Self::Text(value)
+    }
+}
+
+impl From<Embed> for WebhookBody {
+    #[inline]
+    fn from(value: Embed) -> Self {
+        Self::Embed(Box::new(value))
+    }
+}
+
+#[inline]
+fn avatar_url(id: Uuid) -> String {
+    format!("https://minotar.net/helm/{}", Simple::from_uuid(id))
+}
 
+/// Queues a message for the webhook without waiting for it to be sent.
 #[inline]
 pub fn schedule_send_discord(
     state: &AppState,
     sender: Cow<'static, str>,
     sender_id: Option<Uuid>,
-    content: String,
+    body: impl Into<WebhookBody>,
 ) {
-    spawn(send_discord(
-        state.client.clone(),
-        state.webhook_id.clone(),
-        state.webhook_token.clone(),
-        state.discord_username_regex.clone(),
-        state.formatting_regex.clone(),
-        state.embed_url.clone(),
-        sender,
+    state.outbound.push(OutboundMessage {
+        sender_name: sender,
         sender_id,
-        content,
-    ));
+        body: body.into(),
+        done: None,
+    });
 }
 
+/// Queues a message for the webhook and waits until it's been sent, or
+/// given up on.
 #[inline]
 async fn send_discord(
-    client: Arc<Client>,
-    webhook_id: Id<WebhookMarker>,
-    webhook_token: Arc<str>,
-    discord_username_regex: Arc<Regex>,
-    formatting_regex: Arc<Regex>,
-    embed_url: bool,
-    sender_name: Cow<'static, str>,
+    state: &AppState,
+    sender: Cow<'static, str>,
     sender_id: Option<Uuid>,
-    content: String,
+    body: impl Into<WebhookBody>,
 ) {
-    let mut escaped_formatting = formatting_regex.replace_all(&content, "\\$1");
+    let (done, sent) = oneshot::channel();
 
-    if !embed_url {
-        escaped_formatting = Cow::Owned(escaped_formatting.replace(":", "\\:"))
-    }
+    state.outbound.push(OutboundMessage {
+        sender_name: sender,
+        sender_id,
+        body: body.into(),
+        done: Some(done),
+    });
+
+    let _ = sent.await;
+}
 
-    let username = discord_username_regex.replace_all(sender_name.as_ref(), "$1¡$3");
+#[inline]
+pub async fn execute_webhook(
+    state: &AppState,
+    sender_name: &str,
+    sender_id: Option<Uuid>,
+    body: &WebhookBody,
+) -> Result<(), twilight_http::Error> {
+    let username = state
+        .discord_username_regex
+        .replace_all(sender_name, "$1¡$3");
 
-    let mut message_builder = client
-        .execute_webhook(webhook_id, &webhook_token)
-        .content(&escaped_formatting)
+    let mut message_builder = state
+        .client
+        .execute_webhook(state.webhook_id, &state.webhook_token)
         .username(&username);
 
-    let avatar_url = sender_id.map(|id| {
-        format!(
-            "https://minotar.net/helm/{}",
-            Simple::from_uuid(id).to_string()
-        )
-    });
+    let escaped_formatting;
+
+    match body {
+        WebhookBody::Text(content) => {
+            escaped_formatting = minecraft_to_markdown(content, |text| state.escape_discord(text));
+            message_builder = message_builder.content(&escaped_formatting);
+        }
+        WebhookBody::Embed(embed) => {
+            message_builder = message_builder.embeds(slice::from_ref(embed.as_ref()));
+        }
+    }
+
+    let avatar_url = sender_id.map(avatar_url);
 
     if let Some(avatar_url) = avatar_url.as_ref() {
         message_builder = message_builder.avatar_url(avatar_url);
     }
 
-    match message_builder.await {
-        Ok(_) => {}
-        Err(e) => warn!(?e, "failure sending message to webhook"),
+    message_builder.await?;
+    Ok(())
+}
+
+#[inline]
+fn advancement_embed(
+    state: &AppState,
+    player: &str,
+    player_id: Option<Uuid>,
+    kind: AdvancementKind,
+    title: &str,
+) -> Embed {
+    Embed {
+        author: Some(EmbedAuthor {
+            icon_url: player_id.map(avatar_url),
+            name: format!("{player} has {}", kind.verb()),
+            proxy_icon_url: None,
+            url: None,
+        }),
+        color: Some(match kind {
+            AdvancementKind::Task | AdvancementKind::Goal => TASK_COLOR,
+            AdvancementKind::Challenge => CHALLENGE_COLOR,
+        }),
+        description: None,
+        fields: Vec::new(),
+        footer: None,
+        image: None,
+        kind: "rich".to_string(),
+        provider: None,
+        thumbnail: None,
+        timestamp: None,
+        title: Some(
+            state
+                .formatting_regex
+                .replace_all(title, "\\$1")
+                .into_owned(),
+        ),
+        url: None,
+        video: None,
+    }
+}
+
+#[inline]
+fn describe_exit(status: ExitStatus) -> String {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("exit code {code}"),
+        (None, Some(signal)) => format!("signal {signal}"),
+        (None, None) => status.to_string(),
+    }
+}
+
+#[inline]
+pub async fn relay_game_events(
+    state: AppState,
+    mut game_event_receiver: UnboundedReceiver<GameEvent>,
+) -> Result<()> {
+    let mut stopping = false;
+
+    while let Some(event) = game_event_receiver.recv().await {
+        // players don't always get a leave event when the server goes down
+        if matches!(
+            event,
+            GameEvent::ServerLaunching | GameEvent::ServerExited { .. }
+        ) {
+            state.roster.clear();
+            refresh_player_count(&state);
+        }
+
+        match event {
+            GameEvent::ServerStarted { .. }
+            | GameEvent::RconConnected
+            | GameEvent::RconReconnected => {
+                set_server_online(&state, true);
+                spawn(refresh_max_players(state.clone()));
+            }
+            GameEvent::ServerStopping
+            | GameEvent::ServerExited { .. }
+            | GameEvent::RconDisconnected => set_server_online(&state, false),
+            _ => {}
+        }
+
+        let content = match event {
+            GameEvent::Death { victim, message } => {
+                let victim_id = state.lookup_player(&victim);
+                schedule_send_discord(&state, "System".into(), victim_id, message);
+                continue;
+            }
+            GameEvent::Advancement {
+                player,
+                kind,
+                title,
+            } => {
+                if !state.relays_advancement(kind) {
+                    continue;
+                }
+
+                let player_id = state.lookup_player(&player);
+                let embed = advancement_embed(&state, &player, player_id, kind, &title);
+                schedule_send_discord(&state, "System".into(), player_id, embed);
+                continue;
+            }
+            GameEvent::PlayerKicked { player, reason } => {
+                schedule_send_discord(
+                    &state,
+                    "System".into(),
+                    None,
+                    format!("{player} was kicked: {reason}"),
+                );
+                continue;
+            }
+            GameEvent::ServerLaunching => {
+                stopping = false;
+                "Server starting".to_string()
+            }
+            GameEvent::ServerStarted { startup_time } => {
+                format!("Server online (started in {startup_time}s)")
+            }
+            // the server logs this too when we're the ones stopping it
+            GameEvent::ServerStopping if stopping => continue,
+            GameEvent::ServerStopping => {
+                stopping = true;
+                "Server stopping".to_string()
+            }
+            GameEvent::ServerExited { status } if status.success() => "Server stopped".to_string(),
+            GameEvent::ServerExited { status } if stopping => {
+                format!("Server stopped ({})", describe_exit(status))
+            }
+            GameEvent::ServerExited { status } => {
+                format!("Server crashed ({})", describe_exit(status))
+            }
+            GameEvent::ServerRestartScheduled { delay } => {
+                format!("Server restarting in {}s", delay.as_secs())
+            }
+            GameEvent::ServerRestartsExhausted => {
+                "Server keeps crashing, no longer restarting it".to_string()
+            }
+            GameEvent::RconDisconnected => {
+                "Lost connection to the server, messages from Discord will be delivered once it's back"
+                    .to_string()
+            }
+            // the first connection isn't worth announcing
+            GameEvent::RconConnected => continue,
+            GameEvent::RconReconnected => "Reconnected to the server".to_string(),
+        };
+
+        // lifecycle announcements are sent in order, and the bridge waits on
+        // them while shutting down
+        send_discord(&state, "System".into(), None, content).await;
     }
+
+    Ok(())
 }
 
 #[derive(Debug)]
 pub struct IncomingDiscordMessage {
     pub username: String,
     pub content: String,
+    /// Their account name, which `username` might have more in front of.
+    pub name: String,
+    pub discriminator: u16,
+    pub user_id: Id<UserMarker>,
+    pub top_role: Option<String>,
+    pub color: Option<u32>,
 }
 
 #[inline]
-fn escape_for_component(inp: &str) -> String {
-    inp.replace("\\", "\\\\").replace("\"", "\\\"")
+pub fn create_announcement(tellraw: &Tellraw, text: &str) -> String {
+    tellraw.command(&Component::text("[Server] ").child(Component::text(text)))
 }
 
 impl IncomingDiscordMessage {
     #[inline]
-    pub fn create_command(self, tellraw_prefix: &str) -> String {
-        format!(
-            r#"{tellraw_prefix} "<{}> {}""#,
-            escape_minecraft(&escape_for_component(&self.username)),
-            escape_minecraft(&escape_for_component(&self.content))
+    fn name_component(&self) -> Component {
+        // only users who haven't picked a unique name yet have a discriminator
+        let tag = if self.discriminator == 0 {
+            format!("@{}", self.name)
+        } else {
+            format!("{}#{:04}", self.name, self.discriminator)
+        };
+
+        let mut card =
+            Component::text(tag).child(Component::text(format!("\nID: {}", self.user_id)));
+
+        if let Some(top_role) = &self.top_role {
+            card = card.child(Component::text(format!("\nTop role: {top_role}")));
+        }
+
+        let card = card.child(Component::text("\nClick to mention").color(NamedColor::Gray));
+
+        let mut name = Component::text(format!("<{}>", self.username));
+
+        if let Some(color) = self.color {
+            name = name.color(Color::Hex(color));
+        }
+
+        name.hover_text(card)
+            .click(ClickEvent::SuggestCommand(format!("@{} ", self.name)))
+    }
+
+    #[inline]
+    fn command_with(&self, tellraw: &Tellraw, content: &str) -> String {
+        tellraw.command(
+            &Component::text("")
+                .child(self.name_component())
+                .child(Component::text(" "))
+                .children(markdown::parse(content)),
+        )
+    }
+
+    /// The `tellraw` command for this message, with the content cut short if
+    /// the whole thing would be too long for the server to take.
+    #[inline]
+    pub fn create_command(&self, tellraw: &Tellraw) -> String {
+        let command = self.command_with(tellraw, &self.content);
+
+        let Some(max_length) = tellraw.max_length else {
+            return command;
+        };
+
+        if command.len() <= max_length || self.content.is_empty() {
+            return command;
+        }
+
+        let cuts: Vec<usize> = self
+            .content
+            .char_indices()
+            .map(|(index, _)| index)
+            .collect();
+        let truncated = |cut: usize| format!("{}…", &self.content[..cuts[cut]]);
+
+        // markdown makes the length grow unevenly, so this finds a cut that
+        // fits rather than the longest one
+        let (mut fits, mut too_long) = (0, cuts.len());
+
+        while too_long - fits > 1 {
+            let middle = fits.midpoint(too_long);
+
+            if self.command_with(tellraw, &truncated(middle)).len() <= max_length {
+                fits = middle;
+            } else {
+                too_long = middle;
+            }
+        }
+
+        self.command_with(tellraw, &truncated(fits))
+    }
+}
+
+#[inline]
+fn command_option(name: &str, description: &str) -> CommandOption {
+    CommandOption {
+        autocomplete: None,
+        channel_types: None,
+        choices: None,
+        description: description.to_string(),
+        description_localizations: None,
+        kind: CommandOptionType::String,
+        max_length: None,
+        max_value: None,
+        min_length: None,
+        min_value: None,
+        name: name.to_string(),
+        name_localizations: None,
+        options: None,
+        required: Some(true),
+    }
+}
+
+#[inline]
+// dm_permission is deprecated, but still has to be filled in
+#[allow(deprecated)]
+fn slash_command(name: &str, description: &str, options: Vec<CommandOption>) -> Command {
+    Command {
+        application_id: None,
+        contexts: Some(vec![InteractionContextType::Guild]),
+        default_member_permissions: None,
+        dm_permission: None,
+        description: description.to_string(),
+        description_localizations: None,
+        guild_id: None,
+        id: None,
+        integration_types: None,
+        kind: CommandType::ChatInput,
+        name: name.to_string(),
+        name_localizations: None,
+        nsfw: None,
+        options,
+        version: Id::new(1),
+    }
+}
+
+#[inline]
+async fn register_commands(state: &AppState, application_id: Id<ApplicationMarker>) {
+    let mut commands = vec![
+        slash_command("list", "Lists online players", Vec::new()),
+        slash_command("status", "Shows the server's status", Vec::new()),
+    ];
+
+    if state.console_permissions.is_some() {
+        commands.push(slash_command(
+            "console",
+            "Runs a command on the server",
+            vec![command_option("command", "The command to run")],
+        ));
+    }
+
+    match state
+        .client
+        .interaction(application_id)
+        .set_global_commands(&commands)
+        .await
+    {
+        Ok(_) => info!("registered slash commands"),
+        Err(e) => warn!(?e, "failed to register slash commands"),
+    }
+}
+
+#[inline]
+async fn run_slash_command(
+    state: &AppState,
+    interaction: &Interaction,
+    data: &CommandData,
+) -> String {
+    match data.name.as_str() {
+        "list" => describe_player_list(state, &list_players(state).await),
+        "status" => match ping(&state.server_address).await {
+            Ok(status) => {
+                let full_stat = match &state.query_address {
+                    Some(query_address) => query(query_address)
+                        .await
+                        .inspect_err(|e| warn!(?e, "failed to query server"))
+                        .ok(),
+                    None => None,
+                };
+
+                describe_status(state, &status, full_stat.as_ref())
+            }
+            Err(e) => {
+                warn!(?e, "failed to ping server");
+                "The server isn't responding".to_string()
+            }
+        },
+        "console" => {
+            let (Some(member), Some(CommandOptionValue::String(command))) = (
+                interaction.member.as_ref(),
+                data.options.first().map(|option| &option.value),
+            ) else {
+                return "This command can only be used in a server".to_string();
+            };
+
+            let Some(user) = member.user.as_ref() else {
+                return "This command can only be used in a server".to_string();
+            };
+
+            match run_as_member(state, user.id, &user.name, &member.roles, command).await {
+                Ok(reply) => reply,
+                Err(e) => {
+                    warn!(?e, "failed to run console command");
+                    "Failed to run the command, check the bridge logs".to_string()
+                }
+            }
+        }
+        _ => "Unknown command".to_string(),
+    }
+}
+
+#[inline]
+async fn handle_interaction(state: AppState, interaction: Box<InteractionCreate>) {
+    let Some(InteractionData::ApplicationCommand(data)) = &interaction.data else {
+        return;
+    };
+
+    let interaction_client = state.client.interaction(interaction.application_id);
+
+    // commands can take a while over rcon, so we have to acknowledge them first
+    if let Err(e) = interaction_client
+        .create_response(
+            interaction.id,
+            &interaction.token,
+            &InteractionResponse {
+                kind: InteractionResponseType::DeferredChannelMessageWithSource,
+                data: None,
+            },
         )
+        .await
+    {
+        warn!(?e, "failed to acknowledge slash command");
+        return;
+    }
+
+    let reply = run_slash_command(&state, &interaction, data).await;
+
+    if let Err(e) = interaction_client
+        .update_response(&interaction.token)
+        .content(Some(&reply))
+        .await
+    {
+        warn!(?e, "failed to respond to slash command");
+    }
+}
+
+#[inline]
+fn update_presence(shard: &Shard, presence: Presence) {
+    match presence.to_update() {
+        Ok(update) => shard.command(&update),
+        Err(e) => warn!(?e, "failed to build presence update"),
     }
 }
 
 #[inline]
 pub async fn read_discord(
+    state: AppState,
     token: String,
     channel_id: Id<ChannelMarker>,
-    webhook_id: Id<WebhookMarker>,
     discord_message_sender: UnboundedSender<IncomingDiscordMessage>,
 ) -> Result<()> {
     let mut shard = Shard::new(
         ShardId::ONE,
         token,
-        Intents::MESSAGE_CONTENT | Intents::GUILD_MESSAGES,
+        Intents::MESSAGE_CONTENT | Intents::GUILD_MESSAGES | Intents::GUILDS,
     );
 
-    while let Some(event) = shard.next_event(EventTypeFlags::MESSAGE_CREATE).await {
+    let event_types = EventTypeFlags::MESSAGE_CREATE
+        | EventTypeFlags::READY
+        | EventTypeFlags::INTERACTION_CREATE
+        | EventTypeFlags::GUILD_CREATE
+        | EventTypeFlags::ROLE_CREATE
+        | EventTypeFlags::ROLE_UPDATE
+        | EventTypeFlags::ROLE_DELETE;
+
+    let mut presence_receiver = state.presence.subscribe();
+
+    loop {
+        let event = select! {
+            event = shard.next_event(event_types) => event,
+            Ok(()) = presence_receiver.changed() => {
+                let presence = *presence_receiver.borrow_and_update();
+                update_presence(&shard, presence);
+                continue;
+            }
+        };
+
+        let Some(event) = event else {
+            break;
+        };
+
         let Ok(event) = event else {
             warn!(source = ?event.unwrap_err(), "event error");
             continue;
         };
 
         match event {
+            Event::Ready(ready) => {
+                // a fresh session starts without any presence
+                update_presence(&shard, *state.presence.borrow());
+
+                let state = state.clone();
+                spawn(async move { register_commands(&state, ready.application.id).await });
+            }
+            Event::InteractionCreate(interaction) => {
+                spawn(handle_interaction(state.clone(), interaction));
+            }
             Event::MessageCreate(event) => {
                 if event.channel_id != channel_id
-                    || event.webhook_id.is_some_and(|id| id == webhook_id)
+                    || event.webhook_id.is_some_and(|id| id == state.webhook_id)
                     || event.content.is_empty()
                 {
                     continue;
                 }
 
+                if state.console_permissions.is_some()
+                    && !event.author.bot
+                    && event.content.starts_with(state.console_prefix.as_ref())
+                {
+                    spawn(handle_console_message(state.clone(), event));
+                    continue;
+                }
+
                 let escaped_name = escape_minecraft(&event.author.name);
+                let roles = event
+                    .member
+                    .as_ref()
+                    .map_or(&[][..], |member| member.roles.as_slice());
+
                 discord_message_sender.send(IncomingDiscordMessage {
                     username: if event.author.bot {
                         format!("[BOT] {escaped_name}")
                     } else {
-                        escaped_name
+                        escaped_name.clone()
                     },
                     content: escape_minecraft(&event.content),
+                    name: escaped_name,
+                    discriminator: event.author.discriminator,
+                    user_id: event.author.id,
+                    top_role: state.roles.top_role(roles).as_deref().map(escape_minecraft),
+                    color: state.roles.color(roles),
                 })?;
             }
+            Event::GuildCreate(guild) => {
+                if let GuildCreate::Available(guild) = guild.as_ref() {
+                    for role in &guild.roles {
+                        state.roles.insert(role);
+                    }
+                }
+            }
+            Event::RoleCreate(event) => state.roles.insert(&event.role),
+            Event::RoleUpdate(event) => state.roles.insert(&event.role),
+            Event::RoleDelete(event) => state.roles.remove(event.role_id),
             _ => continue,
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::component::EventFormat;
+
+    fn message(content: &str) -> IncomingDiscordMessage {
+        IncomingDiscordMessage {
+            username: "Bob".to_string(),
+            content: content.to_string(),
+            name: "bob".to_string(),
+            discriminator: 0,
+            user_id: Id::new(1),
+            top_role: Some("Admin".to_string()),
+            color: Some(0x1ABC9C),
+        }
+    }
+
+    fn tellraw(max_length: Option<usize>) -> Tellraw {
+        Tellraw {
+            prefix: "tellraw @a".to_string(),
+            format: EventFormat::Modern,
+            max_length,
+        }
+    }
+
+    #[test]
+    fn keeps_short_messages() {
+        let command = message("hi").create_command(&tellraw(Some(1446)));
+
+        assert!(
+            command.ends_with(r#"{"text":" "},{"text":"hi"}]}"#),
+            "{command}"
+        );
+        assert_eq!(command, message("hi").create_command(&tellraw(None)));
+    }
+
+    #[test]
+    fn cuts_long_messages_short() {
+        let content = "**ü** \\ ".repeat(300);
+
+        for max_length in [600, 1000, 1446] {
+            let command = message(&content).create_command(&tellraw(Some(max_length)));
+
+            assert!(
+                command.len() <= max_length,
+                "{} > {max_length}",
+                command.len()
+            );
+            assert!(
+                command.len() > max_length - 100,
+                "{} for {max_length}",
+                command.len()
+            );
+            assert!(command.contains('…'));
+        }
+
+        let command = message(&content).create_command(&tellraw(None));
+        assert!(command.len() > 1446);
+        assert!(!command.contains('…'));
+    }
+}
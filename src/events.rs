@@ -0,0 +1,492 @@
+use std::{
+    io::SeekFrom, os::unix::fs::MetadataExt, path::Path, process::ExitStatus, sync::LazyLock,
+    time::Duration,
+};
+
+use anyhow::Result;
+use regex::Regex;
+use tokio::{
+    fs::{File, metadata},
+    io::{AsyncBufReadExt, AsyncSeekExt, BufReader},
+    sync::mpsc::UnboundedSender,
+    time::sleep,
+};
+use tracing::{info, warn};
+
+const LOG_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const MAX_OPEN_DELAY: Duration = Duration::from_secs(30);
+
+/// Death message translations from the vanilla `en_us` language file, with the
+/// victim (`%1$s`) stripped from the front and the killer and item they used
+/// written as `%s`. Older versions say "whilst" instead of "while", so both
+/// are accepted.
+const DEATH_MESSAGES: &[&str] = &[
+    "was squashed by a falling anvil",
+    "was squashed by a falling anvil while fighting %s",
+    "was shot by %s",
+    "was shot by %s using %s",
+    "was pricked to death",
+    "walked into a cactus while trying to escape %s",
+    "was squished too much",
+    "was squashed by %s",
+    "was roasted in dragon's breath",
+    "was roasted in dragon's breath by %s",
+    "drowned",
+    "drowned while trying to escape %s",
+    "died from dehydration",
+    "died from dehydration while trying to escape %s",
+    "was killed by even more magic",
+    "blew up",
+    "was blown up by %s",
+    "was blown up by %s using %s",
+    "hit the ground too hard",
+    "hit the ground too hard while trying to escape %s",
+    "was squashed by a falling block",
+    "was squashed by a falling block while fighting %s",
+    "was skewered by a falling stalactite",
+    "was skewered by a falling stalactite while fighting %s",
+    "was fireballed by %s",
+    "was fireballed by %s using %s",
+    "went off with a bang",
+    "went off with a bang due to a firework fired from %s by %s",
+    "went off with a bang while fighting %s",
+    "experienced kinetic energy",
+    "experienced kinetic energy while trying to escape %s",
+    "froze to death",
+    "was frozen to death by %s",
+    "died",
+    "died because of %s",
+    "was killed",
+    "was killed while fighting %s",
+    "discovered the floor was lava",
+    "walked into the danger zone due to %s",
+    "went up in flames",
+    "walked into fire while fighting %s",
+    "suffocated in a wall",
+    "suffocated in a wall while fighting %s",
+    "was killed by %s",
+    "was killed by %s using %s",
+    "was killed by magic",
+    "was killed by magic while trying to escape %s",
+    "tried to swim in lava",
+    "tried to swim in lava to escape %s",
+    "was struck by lightning",
+    "was struck by lightning while fighting %s",
+    "was smashed by %s",
+    "was smashed by %s with %s",
+    "was slain by %s",
+    "was slain by %s using %s",
+    "burned to death",
+    "was burned to a crisp while fighting %s",
+    "was burned to a crisp while fighting %s wielding %s",
+    "fell out of the world",
+    "didn't want to live in the same world as %s",
+    "left the confines of this world",
+    "left the confines of this world while fighting %s",
+    "was obliterated by a sonically-charged shriek",
+    "was obliterated by a sonically-charged shriek while trying to escape %s",
+    "was obliterated by a sonically-charged shriek while trying to escape %s wielding %s",
+    "was speared by %s",
+    "was speared by %s using %s",
+    "was impaled on a stalagmite",
+    "was impaled on a stalagmite while fighting %s",
+    "starved to death",
+    "starved to death while fighting %s",
+    "was stung to death",
+    "was stung to death by %s",
+    "was stung to death by %s using %s",
+    "was poked to death by a sweet berry bush",
+    "was poked to death by a sweet berry bush while trying to escape %s",
+    "was killed while trying to hurt %s",
+    "was killed by %s while trying to hurt %s",
+    "was pummeled by %s",
+    "was pummeled by %s using %s",
+    "was impaled by %s",
+    "was impaled by %s with %s",
+    "withered away",
+    "withered away while fighting %s",
+    "was shot by a skull from %s",
+    "was shot by a skull from %s using %s",
+    "was spitballed by %s",
+    "was spitballed by %s using %s",
+    "fell from a high place",
+    "fell off a ladder",
+    "fell while climbing",
+    "fell off scaffolding",
+    "fell off some twisting vines",
+    "fell off some vines",
+    "fell off some weeping vines",
+    "was doomed to fall",
+    "was doomed to fall by %s",
+    "was doomed to fall by %s using %s",
+    "fell too far and was finished by %s",
+    "fell too far and was finished by %s using %s",
+];
+
+static LINE_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"^\[[^\]]+\] \[(?P<thread>[^\]/]+)/(?P<level>[A-Z]+)\](?: \[[^\]]*\])?: (?P<message>.*)$",
+    )
+    .unwrap()
+});
+
+static DEATH_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    let alternatives = DEATH_MESSAGES
+        .iter()
+        .map(|message| {
+            regex::escape(message)
+                .replace("%s", ".+")
+                .replace("while", "(?:while|whilst)")
+        })
+        .collect::<Vec<_>>()
+        .join("|");
+
+    Regex::new(&format!(
+        r"^(?P<victim>[A-Za-z0-9_]{{1,16}}) (?:{alternatives})$"
+    ))
+    .unwrap()
+});
+
+static ADVANCEMENT_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"^(?P<player>[A-Za-z0-9_]{1,16}) has (?P<kind>made the advancement|reached the goal|completed the challenge) \[(?P<title>.+)\]$",
+    )
+    .unwrap()
+});
+
+static DONE_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"^Done \((?P<time>[0-9.,]+)s\)!"#).unwrap());
+
+static KICKED_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"^(?:\[(?P<source>[^:\]]+): )?Kicked (?P<player>[A-Za-z0-9_]{1,16}): (?P<reason>.*)$",
+    )
+    .unwrap()
+});
+
+/// Strips the timestamp, thread and level from a line of server output.
+#[inline]
+pub fn strip_log_prefix(line: &str) -> &str {
+    LINE_REGEX
+        .captures(line)
+        .and_then(|captures| captures.name("message"))
+        .map_or(line, |message| message.as_str())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdvancementKind {
+    Task,
+    Goal,
+    Challenge,
+}
+
+impl AdvancementKind {
+    #[inline]
+    const fn from_verb(verb: &str) -> Option<Self> {
+        match verb.as_bytes() {
+            b"made the advancement" => Some(Self::Task),
+            b"reached the goal" => Some(Self::Goal),
+            b"completed the challenge" => Some(Self::Challenge),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub const fn verb(self) -> &'static str {
+        match self {
+            Self::Task => "made the advancement",
+            Self::Goal => "reached the goal",
+            Self::Challenge => "completed the challenge",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GameEvent {
+    Death {
+        victim: String,
+        message: String,
+    },
+    Advancement {
+        player: String,
+        kind: AdvancementKind,
+        title: String,
+    },
+    ServerLaunching,
+    ServerStarted {
+        startup_time: String,
+    },
+    ServerStopping,
+    ServerExited {
+        status: ExitStatus,
+    },
+    ServerRestartScheduled {
+        delay: Duration,
+    },
+    ServerRestartsExhausted,
+    RconConnected,
+    RconDisconnected,
+    RconReconnected,
+    PlayerKicked {
+        player: String,
+        reason: String,
+    },
+}
+
+impl GameEvent {
+    /// Parses a single line of server output. Lines that aren't logged by the
+    /// server thread at `INFO` are ignored, as are lines the server doesn't
+    /// produce itself (chat, `/say`, `/me`), since their message always starts
+    /// with a bracket or an asterisk.
+    #[inline]
+    pub fn parse(line: &str) -> Option<Self> {
+        let captures = LINE_REGEX.captures(line.trim_end())?;
+
+        if &captures["thread"] != "Server thread" || &captures["level"] != "INFO" {
+            return None;
+        }
+
+        let message = captures.name("message")?.as_str();
+
+        if let Some(captures) = DONE_REGEX.captures(message) {
+            return Some(Self::ServerStarted {
+                startup_time: captures["time"].to_string(),
+            });
+        }
+
+        if message == "Stopping server" {
+            return Some(Self::ServerStopping);
+        }
+
+        if let Some(captures) = KICKED_REGEX.captures(message) {
+            let mut reason = &captures["reason"];
+
+            if captures.name("source").is_some() {
+                reason = reason.strip_suffix(']')?;
+            }
+
+            return Some(Self::PlayerKicked {
+                player: captures["player"].to_string(),
+                reason: reason.to_string(),
+            });
+        }
+
+        if let Some(captures) = ADVANCEMENT_REGEX.captures(message) {
+            return Some(Self::Advancement {
+                player: captures["player"].to_string(),
+                kind: AdvancementKind::from_verb(&captures["kind"])?,
+                title: captures["title"].to_string(),
+            });
+        }
+
+        if let Some(captures) = DEATH_REGEX.captures(message) {
+            return Some(Self::Death {
+                victim: captures["victim"].to_string(),
+                message: message.to_string(),
+            });
+        }
+
+        None
+    }
+}
+
+#[inline]
+async fn open_log(path: &Path, from: SeekFrom) -> Result<(BufReader<File>, u64)> {
+    let mut file = File::open(path).await?;
+    let inode = file.metadata().await?.ino();
+    file.seek(from).await?;
+
+    Ok((BufReader::new(file), inode))
+}
+
+/// Opens the log, waiting for it if the server hasn't created it yet. A log
+/// we had to wait for is new, so it's read from the start whatever `from` is.
+#[inline]
+async fn wait_for_log(path: &Path, mut from: SeekFrom) -> (BufReader<File>, u64) {
+    let mut delay = LOG_POLL_INTERVAL;
+
+    loop {
+        match open_log(path, from).await {
+            Ok(opened) => return opened,
+            Err(e) => warn!(?e, ?path, ?delay, "failed to open server log, retrying"),
+        }
+
+        from = SeekFrom::Start(0);
+        sleep(delay).await;
+        delay = (delay * 2).min(MAX_OPEN_DELAY);
+    }
+}
+
+/// Follows the server log the same way `tail -F` would, for when we aren't the
+/// ones launching the server. The server moves `latest.log` aside and starts a
+/// new one every time it boots, so we reopen the file from the start whenever
+/// it's replaced or truncated.
+#[inline]
+pub async fn tail_log(path: String, game_event_sender: UnboundedSender<GameEvent>) -> Result<()> {
+    let path = Path::new(&path);
+    let (mut reader, mut inode) = wait_for_log(path, SeekFrom::End(0)).await;
+    let mut line = Vec::new();
+
+    info!(?path, "tailing server log");
+
+    loop {
+        match reader.read_until(b'\n', &mut line).await {
+            Ok(0) => {}
+            Ok(_) => {
+                if line.ends_with(b"\n") {
+                    if let Some(event) = GameEvent::parse(&String::from_utf8_lossy(&line)) {
+                        game_event_sender.send(event)?;
+                    }
+
+                    line.clear();
+                }
+
+                continue;
+            }
+            Err(e) => {
+                warn!(?e, ?path, "failed to read server log, reopening");
+                (reader, inode) = wait_for_log(path, SeekFrom::Start(0)).await;
+                line.clear();
+                continue;
+            }
+        }
+
+        sleep(LOG_POLL_INTERVAL).await;
+
+        let Ok(current) = metadata(path).await else {
+            continue;
+        };
+
+        let Ok(position) = reader.stream_position().await else {
+            continue;
+        };
+
+        if current.ino() != inode || current.len() < position {
+            info!(?path, "server log was replaced, reopening");
+            (reader, inode) = wait_for_log(path, SeekFrom::Start(0)).await;
+            line.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline]
+    fn parse(message: &str) -> Option<GameEvent> {
+        GameEvent::parse(&format!("[12:34:56] [Server thread/INFO]: {message}\n"))
+    }
+
+    #[inline]
+    fn death(victim: &str, message: &str) -> Option<GameEvent> {
+        Some(GameEvent::Death {
+            victim: victim.to_string(),
+            message: message.to_string(),
+        })
+    }
+
+    #[test]
+    fn parses_deaths() {
+        for message in [
+            "Steve was killed",
+            "Steve died",
+            "Steve blew up",
+            "Steve drowned",
+            "Steve was slain by Zombie",
+            "Steve was slain by Alex using [Sharp Stick]",
+            "Steve was shot by Skeleton",
+            "Steve was spitballed by Llama",
+            "Steve was blown up by Creeper",
+            "Steve was killed by [Intentional Game Design]",
+            "Steve hit the ground too hard while trying to escape Zombie",
+            "Steve walked into a cactus whilst trying to escape Zombie",
+            "Steve went off with a bang due to a firework fired from [Rocket] by Alex",
+            "Steve fell out of the world",
+        ] {
+            assert_eq!(parse(message), death("Steve", message), "{message}");
+        }
+    }
+
+    #[test]
+    fn ignores_lines_that_only_start_like_deaths() {
+        assert_eq!(parse("Steve blew up the bridge lol"), None);
+        assert_eq!(parse("Steve died inside"), None);
+        assert_eq!(parse("Steve drowned his sorrows"), None);
+        assert_eq!(parse("Steve was killed earlier"), None);
+    }
+
+    #[test]
+    fn ignores_joins_and_leaves() {
+        // these come from the filter requests, which have the player's UUID
+        assert_eq!(parse("Steve joined the game"), None);
+        assert_eq!(parse("Steve left the game"), None);
+        assert_eq!(parse("Steve lost connection: Disconnected"), None);
+    }
+
+    #[test]
+    fn ignores_chat() {
+        assert_eq!(parse("<Steve> Alex was slain by Zombie"), None);
+        assert_eq!(parse("[Steve] Alex died"), None);
+        assert_eq!(parse("* Steve died"), None);
+    }
+
+    #[test]
+    fn ignores_other_threads_and_levels() {
+        assert_eq!(
+            GameEvent::parse("[12:34:56] [Server thread/WARN]: Steve died"),
+            None
+        );
+        assert_eq!(
+            GameEvent::parse("[12:34:56] [User Authenticator #1/INFO]: Steve died"),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_advancements() {
+        assert_eq!(
+            parse("Steve has made the advancement [Stone Age]"),
+            Some(GameEvent::Advancement {
+                player: "Steve".to_string(),
+                kind: AdvancementKind::Task,
+                title: "Stone Age".to_string(),
+            })
+        );
+        assert_eq!(
+            parse("Steve has reached the goal [Sky's the Limit]"),
+            Some(GameEvent::Advancement {
+                player: "Steve".to_string(),
+                kind: AdvancementKind::Goal,
+                title: "Sky's the Limit".to_string(),
+            })
+        );
+        assert_eq!(
+            parse("Steve has completed the challenge [How Did We Get Here?]"),
+            Some(GameEvent::Advancement {
+                player: "Steve".to_string(),
+                kind: AdvancementKind::Challenge,
+                title: "How Did We Get Here?".to_string(),
+            })
+        );
+        assert_eq!(parse("Steve has made the advancement"), None);
+    }
+
+    #[test]
+    fn parses_lifecycle_and_kicks() {
+        assert_eq!(
+            parse(r#"Done (4.213s)! For help, type "help""#),
+            Some(GameEvent::ServerStarted {
+                startup_time: "4.213".to_string(),
+            })
+        );
+        assert_eq!(parse("Stopping server"), Some(GameEvent::ServerStopping));
+        assert_eq!(
+            parse("[Alex: Kicked Steve: Being rude]"),
+            Some(GameEvent::PlayerKicked {
+                player: "Steve".to_string(),
+                reason: "Being rude".to_string(),
+            })
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::moderation::{Verdict, masked_indices};
+
 #[derive(Debug, Deserialize)]
 pub struct LegacyProfile {
     pub user_display_name: String,
@@ -15,10 +17,41 @@ pub struct LegacyChat {
 }
 
 #[derive(Debug, Serialize)]
-#[repr(transparent)]
 pub struct LegacyChatResponse {
     #[serde(rename = "response")]
     pub pass_through: bool,
+    /// The message with the characters the server should hide replaced by
+    /// `#`, which it only logs. Leaving this out while not passing through
+    /// hides all of it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hashed: Option<String>,
+    /// Which characters to hide, counted in UTF-16 code units. The server
+    /// fails the whole request if this is missing while `hashed` is there.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hashes: Option<Vec<usize>>,
+}
+
+impl LegacyChatResponse {
+    #[inline]
+    pub fn new(text: &str, verdict: Verdict) -> Self {
+        match verdict {
+            Verdict::Allow => Self {
+                pass_through: true,
+                hashed: None,
+                hashes: None,
+            },
+            Verdict::Mask { masked, .. } => Self {
+                pass_through: false,
+                hashes: Some(masked_indices(text, &masked)),
+                hashed: Some(masked),
+            },
+            Verdict::Block { .. } => Self {
+                pass_through: false,
+                hashed: None,
+                hashes: None,
+            },
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -27,3 +60,41 @@ pub struct JoinOrLeaveEvent {
     #[serde(flatten)]
     pub profile: LegacyProfile,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(text: &str, verdict: Verdict) -> String {
+        serde_json::to_string(&LegacyChatResponse::new(text, verdict)).unwrap()
+    }
+
+    #[test]
+    fn writes_mask_with_hashes() {
+        assert_eq!(
+            response(
+                "a 😀 bad #1",
+                Verdict::Mask {
+                    masked: "a 😀 ### #1".to_string(),
+                    rule_id: "words".to_string(),
+                },
+            ),
+            r#"{"response":false,"hashed":"a 😀 ### #1","hashes":[5,6,7]}"#
+        );
+    }
+
+    #[test]
+    fn writes_pass_through_and_block() {
+        assert_eq!(response("hi", Verdict::Allow), r#"{"response":true}"#);
+        assert_eq!(
+            response(
+                "hi",
+                Verdict::Block {
+                    reason: "spam".to_string(),
+                    rule_id: "spam".to_string(),
+                },
+            ),
+            r#"{"response":false}"#
+        );
+    }
+}
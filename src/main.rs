@@ -1,20 +1,54 @@
 mod auth;
+mod component;
+mod console;
 mod content;
 mod discord;
+mod events;
 mod legacy;
+mod markdown;
+mod moderation;
+mod modern;
+mod outbound;
+mod ping;
+mod players;
+mod presence;
+mod query;
 mod rcon;
+mod roles;
+mod roster;
+mod spam;
+mod topic;
 mod wrapper;
 
-use std::{borrow::Cow, pin::Pin, sync::Arc, task::Poll};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::Poll,
+    time::Duration,
+};
 
-use anyhow::Result;
-use auth::Authorized;
+use anyhow::{Result, bail};
+use auth::{Authorized, ModernAuthorized};
 use axum::{Json, Router, extract::State, http::request::Parts, routing::post, serve};
 use base64::{Engine, prelude::BASE64_STANDARD};
-use discord::{read_discord, schedule_send_discord};
-use legacy::{JoinOrLeaveEvent, LegacyChat, LegacyChatResponse};
+use component::{EventFormat, Tellraw};
+use console::{Console, ConsolePermissions};
+use discord::{read_discord, relay_game_events, schedule_send_discord};
+use events::{AdvancementKind, tail_log};
+use legacy::{JoinOrLeaveEvent, LegacyChat, LegacyChatResponse, LegacyProfile};
+use moderation::{Moderation, Verdict, report};
+use modern::{ModernChat, ModernChatResponse};
+use outbound::{OutboundQueue, OverflowPolicy, deliver_outbound};
+use ping::check_health;
+use presence::{Presence, refresh_player_count};
 use regex::Regex;
+use roles::RoleCache;
+use roster::{Roster, format_duration};
 use serde::Deserialize;
+use spam::{SpamAction, SpamGuard, SpamPolicy, coalesce};
 use tokio::{
     main,
     net::TcpListener,
@@ -24,10 +58,12 @@ use tokio::{
         unix::{SignalKind, signal},
     },
     spawn,
-    sync::{mpsc::unbounded_channel, oneshot},
+    sync::{mpsc::unbounded_channel, oneshot, watch},
     task::JoinSet,
+    time::timeout,
 };
-use tracing::{error, info};
+use topic::update_topic;
+use tracing::{error, info, warn};
 use tracing_subscriber::fmt;
 use twilight_http::Client;
 use twilight_model::{
@@ -37,9 +73,12 @@ use twilight_model::{
         marker::{ChannelMarker, WebhookMarker},
     },
 };
-use wrapper::launch_wrapper;
+use uuid::Uuid;
+use wrapper::{RestartMode, RestartPolicy, ShutdownPolicy, launch_wrapper};
+
+use crate::rcon::{MAX_COMMAND_LENGTH, RconClient};
 
-use crate::rcon::RconClient;
+const RELAY_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[inline]
 const fn default_bind_address() -> Cow<'static, str> {
@@ -51,6 +90,66 @@ const fn default_tellraw_prefix() -> Cow<'static, str> {
     Cow::Borrowed("tellraw @a")
 }
 
+#[inline]
+const fn default_true() -> bool {
+    true
+}
+
+#[inline]
+const fn default_restart_delay_secs() -> u64 {
+    5
+}
+
+#[inline]
+const fn default_restart_max_delay_secs() -> u64 {
+    300
+}
+
+#[inline]
+const fn default_restart_max_attempts() -> usize {
+    5
+}
+
+#[inline]
+const fn default_restart_window_secs() -> u64 {
+    600
+}
+
+#[inline]
+const fn default_stop_timeout_secs() -> u64 {
+    60
+}
+
+#[inline]
+const fn default_server_address() -> Cow<'static, str> {
+    Cow::Borrowed("127.0.0.1:25565")
+}
+
+#[inline]
+const fn default_spam_burst() -> u32 {
+    5
+}
+
+#[inline]
+const fn default_spam_refill_secs() -> u64 {
+    2
+}
+
+#[inline]
+const fn default_spam_duplicate_secs() -> u64 {
+    30
+}
+
+#[inline]
+const fn default_outbound_capacity() -> usize {
+    500
+}
+
+#[inline]
+fn default_console_prefix() -> String {
+    "!mc ".to_string()
+}
+
 #[derive(Debug, Deserialize)]
 struct DiscordConfig {
     token: String,
@@ -80,10 +179,68 @@ struct Config {
     allow_role_mention: bool,
     #[serde(default)]
     embed_url: bool,
+    #[serde(default)]
+    text_filtering_version: u8,
     #[serde(default = "default_tellraw_prefix")]
     tellraw_prefix: Cow<'static, str>,
     #[serde(default)]
+    server_version: Option<String>,
+    #[serde(default)]
     rcon: Option<RconConfig>,
+    #[serde(default)]
+    log_file: Option<String>,
+    #[serde(default = "default_true")]
+    relay_advancements: bool,
+    #[serde(default = "default_true")]
+    relay_goals: bool,
+    #[serde(default = "default_true")]
+    relay_challenges: bool,
+    #[serde(default)]
+    restart_policy: RestartMode,
+    #[serde(default = "default_restart_delay_secs")]
+    restart_delay_secs: u64,
+    #[serde(default = "default_restart_max_delay_secs")]
+    restart_max_delay_secs: u64,
+    #[serde(default = "default_restart_max_attempts")]
+    restart_max_attempts: usize,
+    #[serde(default = "default_restart_window_secs")]
+    restart_window_secs: u64,
+    #[serde(default = "default_stop_timeout_secs")]
+    stop_timeout_secs: u64,
+    #[serde(default)]
+    stop_countdown_secs: u64,
+    #[serde(default)]
+    console_permissions_file: Option<String>,
+    #[serde(default = "default_console_prefix")]
+    console_prefix: String,
+    #[serde(default)]
+    console_audit_log: Option<String>,
+    #[serde(default)]
+    topic_interval_secs: Option<u64>,
+    #[serde(default = "default_server_address")]
+    server_address: Cow<'static, str>,
+    #[serde(default)]
+    health_check_secs: Option<u64>,
+    #[serde(default)]
+    query_address: Option<String>,
+    #[serde(default)]
+    moderation_file: Option<String>,
+    #[serde(default)]
+    moderation_log_channel_id: Option<u64>,
+    #[serde(default)]
+    spam_action: SpamAction,
+    #[serde(default = "default_spam_burst")]
+    spam_burst: u32,
+    #[serde(default = "default_spam_refill_secs")]
+    spam_refill_secs: u64,
+    #[serde(default = "default_spam_duplicate_secs")]
+    spam_duplicate_secs: u64,
+    #[serde(default = "default_outbound_capacity")]
+    outbound_capacity: usize,
+    #[serde(default)]
+    outbound_overflow: OverflowPolicy,
+    #[serde(default)]
+    batch_window_ms: Option<u64>,
 }
 
 #[derive(Debug, Clone)]
@@ -95,6 +252,58 @@ struct AppState {
     discord_username_regex: Arc<Regex>,
     formatting_regex: Arc<Regex>,
     embed_url: bool,
+    known_players: Arc<Mutex<HashMap<String, Uuid>>>,
+    relay_advancements: bool,
+    relay_goals: bool,
+    relay_challenges: bool,
+    console: Console,
+    console_permissions: Option<Arc<ConsolePermissions>>,
+    console_prefix: Arc<str>,
+    console_audit_log: Option<Arc<str>>,
+    roster: Roster,
+    roles: RoleCache,
+    presence: Arc<watch::Sender<Presence>>,
+    server_address: Arc<str>,
+    query_address: Option<Arc<str>>,
+    moderation: Option<Arc<Moderation>>,
+    moderation_log_channel_id: Option<Id<ChannelMarker>>,
+    spam_guard: SpamGuard,
+    outbound: OutboundQueue,
+}
+
+impl AppState {
+    #[inline]
+    fn escape_discord<'a>(&self, content: &'a str) -> Cow<'a, str> {
+        let escaped_formatting = self.formatting_regex.replace_all(content, "\\$1");
+
+        if self.embed_url {
+            escaped_formatting
+        } else {
+            Cow::Owned(escaped_formatting.replace(":", "\\:"))
+        }
+    }
+
+    #[inline]
+    const fn relays_advancement(&self, kind: AdvancementKind) -> bool {
+        match kind {
+            AdvancementKind::Task => self.relay_advancements,
+            AdvancementKind::Goal => self.relay_goals,
+            AdvancementKind::Challenge => self.relay_challenges,
+        }
+    }
+
+    #[inline]
+    fn remember_player(&self, profile: &LegacyProfile) {
+        self.known_players
+            .lock()
+            .unwrap()
+            .insert(profile.user_display_name.clone(), profile.user_id);
+    }
+
+    #[inline]
+    fn lookup_player(&self, name: &str) -> Option<Uuid> {
+        self.known_players.lock().unwrap().get(name).copied()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -105,10 +314,7 @@ pub enum OptionalFuture<T, F: Future<Output = T> + Unpin> {
 
 impl<T, F: Future<Output = T> + Unpin> OptionalFuture<T, F> {
     const fn is_some(&self) -> bool {
-        match self {
-            OptionalFuture::Present(_) => true,
-            _ => false,
-        }
+        matches!(self, OptionalFuture::Present(_))
     }
 }
 
@@ -161,6 +367,36 @@ async fn main() -> Result<()> {
     let client = Arc::new(client_builder.build());
     let webhook_id = Id::new(config.webhook_id);
 
+    let (game_event_sender, game_event_receiver) = unbounded_channel();
+    let rcon = config
+        .rcon
+        .map(|rcon| RconClient::new(rcon.host, rcon.pass, game_event_sender.clone()));
+
+    let (command_sender, command_receiver) = unbounded_channel();
+    let console = match &rcon {
+        Some((rcon_client, _)) => Console::Rcon(rcon_client.clone()),
+        None => Console::Wrapper(command_sender),
+    };
+
+    let console_permissions = match config.console_permissions_file {
+        Some(path) => Some(Arc::new(ConsolePermissions::load(Path::new(&path)).await?)),
+        None => None,
+    };
+
+    let moderation = match config.moderation_file {
+        Some(path) => Some(Arc::new(Moderation::load(Path::new(&path)).await?)),
+        None => None,
+    };
+
+    let tellraw = Tellraw {
+        prefix: config.tellraw_prefix.into(),
+        format: match &config.server_version {
+            Some(version) => EventFormat::for_version(version)?,
+            None => EventFormat::default(),
+        },
+        max_length: None,
+    };
+
     let state = AppState {
         client: client.clone(),
         expected_auth_header: format!("Basic {}", BASE64_STANDARD.encode(&config.api_key)).into(),
@@ -169,32 +405,86 @@ async fn main() -> Result<()> {
         discord_username_regex: Arc::new(Regex::new(r#"(?i)(d)(i)(scord)"#)?),
         formatting_regex: Arc::new(Regex::new(r#"([\\_`*>|-~\[\]()#])"#)?),
         embed_url: config.embed_url,
+        known_players: Default::default(),
+        relay_advancements: config.relay_advancements,
+        relay_goals: config.relay_goals,
+        relay_challenges: config.relay_challenges,
+        console,
+        console_permissions,
+        console_prefix: config.console_prefix.into(),
+        console_audit_log: config.console_audit_log.map(Into::into),
+        roster: Roster::default(),
+        roles: RoleCache::default(),
+        presence: Arc::new(watch::Sender::new(Presence::default())),
+        server_address: config.server_address.into(),
+        query_address: config.query_address.map(Into::into),
+        moderation,
+        moderation_log_channel_id: config.moderation_log_channel_id.map(Id::new),
+        spam_guard: SpamGuard::new(SpamPolicy {
+            action: config.spam_action,
+            burst: config.spam_burst,
+            refill: Duration::from_secs(config.spam_refill_secs),
+            duplicate_window: Duration::from_secs(config.spam_duplicate_secs),
+        }),
+        outbound: OutboundQueue::new(config.outbound_capacity, config.outbound_overflow),
     };
 
-    let app = Router::new()
+    // not one of the tasks, since it has to outlive them to announce shutdown
+    spawn(deliver_outbound(
+        state.clone(),
+        config.batch_window_ms.map(Duration::from_millis),
+    ));
+
+    let mut app = Router::new()
         .route("/v1/chatx", post(chat))
         .route("/v1/join", post(join))
-        .route("/v1/leave", post(leave))
-        .with_state(state);
+        .route("/v1/leave", post(leave));
+
+    // the newer protocol can't be authenticated, so it's only served to those
+    // who ask for it
+    match config.text_filtering_version {
+        0 => {}
+        1 => app = app.route("/v2/chat", post(modern_chat)),
+        other => bail!("unsupported TEXT_FILTERING_VERSION {other}"),
+    }
+
+    let app = app.with_state(state.clone());
 
     let listener = TcpListener::bind(config.bind_address.as_ref()).await?;
     let mut tasks = JoinSet::new();
 
     tasks.spawn(async { serve(listener, app).await.map_err(anyhow::Error::from) });
 
+    if let Some(health_check_secs) = config.health_check_secs {
+        tasks.spawn(check_health(
+            state.clone(),
+            state.server_address.to_string(),
+            Duration::from_secs(health_check_secs),
+        ));
+    }
+
     let (discord_message_sender, discord_message_receiver) = unbounded_channel();
     let mut discord_message_receiver = Some(discord_message_receiver);
-    let rcon_client = if let Some(rcon) = config.rcon {
-        Some(RconClient::new(&rcon.host, &rcon.pass).await?)
-    } else {
-        None
-    };
+    let relay_handle = spawn(relay_game_events(state.clone(), game_event_receiver));
 
     let (death_sender, death_receiver) = oneshot::channel();
-    let mut server_launcher = if rcon_client.is_none() {
+    let mut server_launcher = if rcon.is_none() {
         OptionalFuture::Present(spawn(launch_wrapper(
             discord_message_receiver.take().unwrap(),
-            config.tellraw_prefix.to_string(),
+            command_receiver,
+            game_event_sender.clone(),
+            tellraw.clone(),
+            RestartPolicy {
+                mode: config.restart_policy,
+                initial_delay: Duration::from_secs(config.restart_delay_secs),
+                max_delay: Duration::from_secs(config.restart_max_delay_secs),
+                max_restarts: config.restart_max_attempts,
+                window: Duration::from_secs(config.restart_window_secs),
+            },
+            ShutdownPolicy {
+                timeout: Duration::from_secs(config.stop_timeout_secs),
+                countdown: Duration::from_secs(config.stop_countdown_secs),
+            },
             death_receiver,
         )))
     } else {
@@ -202,22 +492,38 @@ async fn main() -> Result<()> {
     };
 
     if let Some((token, channel_id)) = discord_config {
+        if let Some(topic_interval_secs) = config.topic_interval_secs {
+            tasks.spawn(update_topic(
+                state.clone(),
+                channel_id,
+                Duration::from_secs(topic_interval_secs),
+            ));
+        }
+
         tasks.spawn(read_discord(
+            state.clone(),
             token,
             channel_id,
-            webhook_id,
             discord_message_sender,
         ));
     }
 
-    if let Some(rcon_client) = rcon_client {
-        tasks.spawn(rcon_client.handle(
+    if let Some((_rcon_client, rcon_worker)) = rcon {
+        if let Some(log_file) = config.log_file {
+            tasks.spawn(tail_log(log_file, game_event_sender.clone()));
+        }
+
+        tasks.spawn(rcon_worker.handle(
             discord_message_receiver.take().unwrap(),
-            config.tellraw_prefix.to_string(),
+            Tellraw {
+                max_length: Some(MAX_COMMAND_LENGTH),
+                ..tellraw
+            },
         ));
     }
 
     let mut sig_term = signal(SignalKind::terminate())?;
+    let mut server_result = None;
     select! {
         res = tasks.join_next() => {
             error!("task failed {:?}", res)
@@ -228,17 +534,27 @@ async fn main() -> Result<()> {
         _ = sig_term.recv() => {
             info!("sigint")
         }
-        _ = &mut server_launcher => {
+        res = &mut server_launcher => {
             info!("server died");
+            server_result = Some(res);
         }
     }
 
     let _ = death_sender.send(());
-    if server_launcher.is_some() {
-        server_launcher.await??;
+    if server_result.is_none() && server_launcher.is_some() {
+        server_result = Some(server_launcher.await);
     }
     tasks.abort_all();
 
+    drop(game_event_sender);
+    if timeout(RELAY_DRAIN_TIMEOUT, relay_handle).await.is_err() {
+        warn!("timed out announcing shutdown to discord");
+    }
+
+    if let Some(res) = server_result {
+        res??;
+    }
+
     Ok(())
 }
 
@@ -255,22 +571,106 @@ fn has_header_and_matches<P: FnOnce(&str) -> bool>(
         .is_some_and(value_predicate)
 }
 
-const PASS_THROUGH_RESPONSE: LegacyChatResponse = LegacyChatResponse { pass_through: true };
+/// Runs the message past moderation, relaying whatever's left of it.
+#[inline]
+fn relay_chat(state: &AppState, profile: LegacyProfile, text: String) -> Verdict {
+    state.remember_player(&profile);
+
+    let verdict = state
+        .moderation
+        .as_ref()
+        .map_or(Verdict::Allow, |moderation| moderation.check(&text));
+    report(state, &profile, &text, &verdict);
+
+    let relayed = match &verdict {
+        Verdict::Allow => text,
+        Verdict::Mask { masked, .. } => masked.clone(),
+        Verdict::Block { .. } => return verdict,
+    };
+
+    if state.spam_guard.action() != SpamAction::Off
+        && state.spam_guard.is_spam(profile.user_id, &relayed)
+    {
+        match state.spam_guard.action() {
+            SpamAction::Off | SpamAction::Drop => {}
+            SpamAction::Coalesce => coalesce(state, &profile, relayed),
+            SpamAction::Block => {
+                let verdict = Verdict::Block {
+                    reason: "sending messages too quickly".to_string(),
+                    rule_id: "spam".to_string(),
+                };
+
+                if let Some(unreported) = state.spam_guard.take_report(profile.user_id) {
+                    let reported = match unreported {
+                        0 => verdict.clone(),
+                        _ => Verdict::Block {
+                            reason: format!(
+                                "sending messages too quickly, {unreported} more blocked since the last report"
+                            ),
+                            rule_id: "spam".to_string(),
+                        },
+                    };
+
+                    report(state, &profile, &relayed, &reported);
+                }
+
+                return verdict;
+            }
+        }
+
+        return verdict;
+    }
+
+    schedule_send_discord(
+        state,
+        profile.user_display_name.into(),
+        Some(profile.user_id),
+        relayed,
+    );
+
+    verdict
+}
+
+#[inline]
+fn player_joined(state: &AppState, profile: &LegacyProfile) {
+    state.remember_player(profile);
+    state.roster.join(profile);
+    refresh_player_count(state);
+    schedule_send_discord(
+        state,
+        "System".into(),
+        None,
+        format!("{} joined the game", profile.user_display_name),
+    );
+}
+
+#[inline]
+fn player_left(state: &AppState, profile: &LegacyProfile) {
+    state.spam_guard.forget(profile.user_id);
+    let session = state.roster.leave(profile);
+    refresh_player_count(state);
+
+    let content = match session {
+        Some(session) => format!(
+            "{} left the game (played for {})",
+            profile.user_display_name,
+            format_duration(session.duration())
+        ),
+        None => format!("{} left the game", profile.user_display_name),
+    };
+
+    schedule_send_discord(state, "System".into(), None, content);
+}
 
 #[inline]
 async fn chat(
     State(state): State<AppState>,
     _authorized: Authorized,
     Json(chat): Json<LegacyChat>,
-) -> Json<&'static LegacyChatResponse> {
-    schedule_send_discord(
-        &state,
-        chat.profile.user_display_name.into(),
-        Some(chat.profile.user_id),
-        chat.text,
-    );
+) -> Json<LegacyChatResponse> {
+    let verdict = relay_chat(&state, chat.profile, chat.text.clone());
 
-    Json(&PASS_THROUGH_RESPONSE)
+    Json(LegacyChatResponse::new(&chat.text, verdict))
 }
 
 #[inline]
@@ -279,12 +679,7 @@ async fn join(
     _authorized: Authorized,
     Json(join): Json<JoinOrLeaveEvent>,
 ) {
-    schedule_send_discord(
-        &state,
-        "System".into(),
-        None,
-        format!("{} joined the game", join.profile.user_display_name),
-    );
+    player_joined(&state, &join.profile);
 }
 
 #[inline]
@@ -293,10 +688,17 @@ async fn leave(
     _authorized: Authorized,
     Json(leave): Json<JoinOrLeaveEvent>,
 ) {
-    schedule_send_discord(
-        &state,
-        "System".into(),
-        None,
-        format!("{} left the game", leave.profile.user_display_name),
-    );
+    player_left(&state, &leave.profile);
+}
+
+#[inline]
+async fn modern_chat(
+    State(state): State<AppState>,
+    _authorized: ModernAuthorized,
+    Json(chat): Json<ModernChat>,
+) -> Json<ModernChatResponse> {
+    let (profile, text) = chat.into_parts();
+    let verdict = relay_chat(&state, profile, text.clone());
+
+    Json(ModernChatResponse::new(&text, verdict))
 }
@@ -0,0 +1,333 @@
+use crate::component::{ClickEvent, Component, NamedColor};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Style {
+    Bold,
+    Italic,
+    Underline,
+    Strikethrough,
+    Spoiler,
+}
+
+/// Longer delimiters come first, so `**` isn't read as two `*`s.
+const DELIMITERS: &[(&str, Style)] = &[
+    ("**", Style::Bold),
+    ("__", Style::Underline),
+    ("~~", Style::Strikethrough),
+    ("||", Style::Spoiler),
+    ("*", Style::Italic),
+    ("_", Style::Italic),
+];
+
+impl Style {
+    #[inline]
+    fn apply(self, inner: &str) -> Component {
+        let children = parse(inner);
+        let component = Component::text("");
+
+        let component = match self {
+            Self::Bold => component.bold(),
+            Self::Italic => component.italic(),
+            Self::Underline => component.underlined(),
+            Self::Strikethrough => component.strikethrough(),
+            // hovering over the scrambled text shows what it says
+            Self::Spoiler => component
+                .obfuscated()
+                .hover_text(Component::text("").children(children.clone())),
+        };
+
+        component.children(children)
+    }
+}
+
+#[inline]
+fn is_word_char(c: Option<char>) -> bool {
+    c.is_some_and(char::is_alphanumeric)
+}
+
+/// Whether `delimiter` has to touch the text it wraps, like `*` and `_` do,
+/// so `2 * 3 * 4` stays as it is.
+#[inline]
+fn is_flanking(delimiter: &str) -> bool {
+    delimiter.starts_with(['*', '_'])
+}
+
+/// Finds where the span `text` starts with ends, skipping over anything
+/// escaped or in code, since the delimiter doesn't count there.
+#[inline]
+fn find_closing(text: &str, delimiter: &str) -> Option<usize> {
+    let mut index = 0;
+
+    while let Some(c) = text[index..].chars().next() {
+        let rest = &text[index..];
+
+        if c == '\\' {
+            index += 1 + rest[1..].chars().next().map_or(0, char::len_utf8);
+            continue;
+        }
+
+        if c == '`'
+            && let Some(end) = rest[1..].find('`')
+        {
+            index += end + 2;
+            continue;
+        }
+
+        if let Some(after) = rest.strip_prefix(delimiter) {
+            // `*` and `_` shouldn't end on half of a `**` or `__`
+            if delimiter.len() == 1 && after.starts_with(delimiter) {
+                index += 2;
+                continue;
+            }
+
+            let after = after.chars().next();
+            let before = text[..index].chars().next_back();
+            let touches = !(is_flanking(delimiter) && before.is_some_and(char::is_whitespace));
+
+            if index > 0 && touches && !(delimiter == "_" && is_word_char(after)) {
+                return Some(index);
+            }
+        }
+
+        index += c.len_utf8();
+    }
+
+    None
+}
+
+/// How much of `text` is a link, if it starts with one. Punctuation at the
+/// end is left off, since it's far more likely to end the sentence than the
+/// link, unless it closes a bracket from inside the link.
+#[inline]
+fn link_length(text: &str) -> Option<usize> {
+    let scheme = ["https://", "http://"]
+        .into_iter()
+        .find(|scheme| text.starts_with(scheme))?;
+
+    let mut link = &text[..text.find(char::is_whitespace).unwrap_or(text.len())];
+
+    loop {
+        let mut trimmed = link.trim_end_matches(['.', ',', ':', ';', '!', '?', '\'', '"']);
+
+        if trimmed.ends_with(')') && trimmed.matches(')').count() > trimmed.matches('(').count() {
+            trimmed = &trimmed[..trimmed.len() - 1];
+        }
+
+        if trimmed.len() == link.len() {
+            break;
+        }
+
+        link = trimmed;
+    }
+
+    (link.len() > scheme.len()).then_some(link.len())
+}
+
+#[inline]
+fn link(url: &str) -> Component {
+    Component::text(url)
+        .underlined()
+        .click(ClickEvent::OpenUrl(url.to_string()))
+}
+
+/// Turns Discord markdown into text components styled to match, as far as
+/// the game can show it.
+#[inline]
+pub fn parse(text: &str) -> Vec<Component> {
+    let mut components = Vec::new();
+    let mut plain = String::new();
+    let mut previous = None;
+    let mut index = 0;
+
+    let flush = |plain: &mut String, components: &mut Vec<Component>| {
+        if !plain.is_empty() {
+            components.push(Component::text(std::mem::take(plain)));
+        }
+    };
+
+    'outer: while let Some(c) = text[index..].chars().next() {
+        let rest = &text[index..];
+
+        if c == '\\'
+            && let Some(escaped) = rest[1..].chars().next()
+            && escaped.is_ascii_punctuation()
+        {
+            plain.push(escaped);
+            previous = Some(escaped);
+            index += 1 + escaped.len_utf8();
+            continue;
+        }
+
+        if c == '`' {
+            let fence = if rest.starts_with("```") { "```" } else { "`" };
+
+            if let Some(end) = rest[fence.len()..].find(fence) {
+                flush(&mut plain, &mut components);
+
+                let code = &rest[fence.len()..fence.len() + end];
+                components.push(Component::text(code).color(NamedColor::Gray));
+
+                previous = Some('`');
+                index += end + 2 * fence.len();
+                continue;
+            }
+        }
+
+        if !is_word_char(previous)
+            && let Some(length) = link_length(rest)
+        {
+            flush(&mut plain, &mut components);
+            components.push(link(&rest[..length]));
+
+            previous = rest[..length].chars().last();
+            index += length;
+            continue;
+        }
+
+        // links in angle brackets don't get embeds, but are still links
+        if c == '<'
+            && let Some(end) = rest.find('>')
+            && !rest[1..end].contains(char::is_whitespace)
+            && link_length(&rest[1..end]).is_some()
+        {
+            flush(&mut plain, &mut components);
+            components.push(link(&rest[1..end]));
+
+            previous = Some('>');
+            index += end + 1;
+            continue;
+        }
+
+        for &(delimiter, style) in DELIMITERS {
+            // `_` only starts italics at the start of a word, so snake_case
+            // stays as it is
+            if !rest.starts_with(delimiter) || (delimiter == "_" && is_word_char(previous)) {
+                continue;
+            }
+
+            let inner_start = delimiter.len();
+
+            if is_flanking(delimiter)
+                && rest[inner_start..]
+                    .chars()
+                    .next()
+                    .is_none_or(char::is_whitespace)
+            {
+                continue;
+            }
+
+            if let Some(end) = find_closing(&rest[inner_start..], delimiter) {
+                flush(&mut plain, &mut components);
+                components.push(style.apply(&rest[inner_start..inner_start + end]));
+
+                previous = delimiter.chars().last();
+                index += end + 2 * delimiter.len();
+                continue 'outer;
+            }
+        }
+
+        plain.push(c);
+        previous = Some(c);
+        index += c.len_utf8();
+    }
+
+    flush(&mut plain, &mut components);
+    components
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> Component {
+        Component::text(text)
+    }
+
+    fn styled(style: Style, children: Vec<Component>) -> Component {
+        let component = match style {
+            Style::Bold => text("").bold(),
+            Style::Italic => text("").italic(),
+            Style::Strikethrough => text("").strikethrough(),
+            _ => unreachable!(),
+        };
+
+        component.children(children)
+    }
+
+    #[test]
+    fn leaves_spaced_out_asterisks() {
+        assert_eq!(parse("2 * 3 * 4"), vec![text("2 * 3 * 4")]);
+        assert_eq!(parse("a ** b ** c"), vec![text("a ** b ** c")]);
+        assert_eq!(parse("*a *"), vec![text("*a *")]);
+        assert_eq!(parse("_ a_"), vec![text("_ a_")]);
+    }
+
+    #[test]
+    fn styles_touching_delimiters() {
+        assert_eq!(
+            parse("2 *3* 4"),
+            vec![
+                text("2 "),
+                styled(Style::Italic, vec![text("3")]),
+                text(" 4")
+            ]
+        );
+        assert_eq!(
+            parse("**a b**"),
+            vec![styled(Style::Bold, vec![text("a b")])]
+        );
+        assert_eq!(parse("snake_case_name"), vec![text("snake_case_name")]);
+    }
+
+    #[test]
+    fn nests_styles() {
+        assert_eq!(
+            parse("**bold _both_** ~~gone~~"),
+            vec![
+                styled(
+                    Style::Bold,
+                    vec![text("bold "), styled(Style::Italic, vec![text("both")])]
+                ),
+                text(" "),
+                styled(Style::Strikethrough, vec![text("gone")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_unclosed_delimiters() {
+        assert_eq!(parse("**a"), vec![text("**a")]);
+        assert_eq!(parse("a_"), vec![text("a_")]);
+        assert_eq!(parse("||secret"), vec![text("||secret")]);
+        assert_eq!(parse("`code"), vec![text("`code")]);
+    }
+
+    #[test]
+    fn skips_escaped_delimiters() {
+        assert_eq!(parse(r"\*a\*"), vec![text("*a*")]);
+        assert_eq!(
+            parse(r"*a \* b*"),
+            vec![styled(Style::Italic, vec![text("a * b")])]
+        );
+        assert_eq!(
+            parse("*`*`*"),
+            vec![styled(
+                Style::Italic,
+                vec![text("*").color(NamedColor::Gray)]
+            )]
+        );
+    }
+
+    #[test]
+    fn hides_spoilers() {
+        assert_eq!(
+            parse("|| it was him ||"),
+            vec![
+                Component::text("")
+                    .obfuscated()
+                    .hover_text(Component::text("").child(text(" it was him ")))
+                    .child(text(" it was him "))
+            ]
+        );
+    }
+}
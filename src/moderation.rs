@@ -0,0 +1,272 @@
+use std::{ops::Range, path::Path};
+
+use anyhow::Result;
+use regex::Regex;
+use serde::Deserialize;
+use tokio::{fs::read_to_string, spawn};
+use tracing::{info, warn};
+
+use crate::{AppState, legacy::LegacyProfile};
+
+#[derive(Debug, Default, Deserialize)]
+struct RuleList {
+    /// Matched case-insensitively, as whole words.
+    #[serde(default)]
+    words: Vec<String>,
+    #[serde(default)]
+    patterns: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ModerationFile {
+    #[serde(default)]
+    block: RuleList,
+    #[serde(default)]
+    mask: RuleList,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// The message with every matched character replaced by `#`.
+    Mask {
+        masked: String,
+        /// The first rule that matched, which the newer filter protocol
+        /// passes on to the server.
+        rule_id: String,
+    },
+    Block {
+        reason: String,
+        rule_id: String,
+    },
+}
+
+/// Which characters of `text` were masked, counted in UTF-16 code units like
+/// both filter protocols expect. Whatever was masked is now a `#` that wasn't
+/// there before.
+#[inline]
+pub fn masked_indices(text: &str, masked: &str) -> Vec<usize> {
+    text.encode_utf16()
+        .zip(masked.encode_utf16())
+        .enumerate()
+        .filter(|&(_, (original, masked))| masked == u16::from(b'#') && original != masked)
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// A compiled rule, identified by its pattern, or `words` for the word list.
+#[derive(Debug)]
+struct Rule {
+    id: String,
+    regex: Regex,
+}
+
+/// Chat rules loaded from a file. Blocked messages are hidden entirely, while
+/// masked ones only have the offending parts hidden.
+#[derive(Debug)]
+pub struct Moderation {
+    block: Vec<Rule>,
+    mask: Vec<Rule>,
+}
+
+#[inline]
+fn compile(rules: RuleList) -> Result<Vec<Rule>> {
+    // an empty entry, say from a trailing comma, would match every message
+    let mut compiled = rules
+        .patterns
+        .into_iter()
+        .filter(|pattern| !pattern.trim().is_empty())
+        .map(|pattern| {
+            Ok(Rule {
+                regex: Regex::new(&pattern)?,
+                id: pattern,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let words = rules
+        .words
+        .iter()
+        .map(|word| word.trim())
+        .filter(|word| !word.is_empty())
+        .map(regex::escape)
+        .collect::<Vec<_>>();
+
+    if !words.is_empty() {
+        let words = words.join("|");
+
+        compiled.push(Rule {
+            id: "words".to_string(),
+            regex: Regex::new(&format!(r"(?i)\b(?:{words})\b"))?,
+        });
+    }
+
+    Ok(compiled)
+}
+
+/// Replaces every character overlapping one of `ranges` with `#`, once for
+/// each UTF-16 code unit, since that's what the server counts in.
+#[inline]
+fn mask(text: &str, ranges: &[Range<usize>]) -> String {
+    let mut masked = String::with_capacity(text.len());
+
+    for (index, character) in text.char_indices() {
+        if ranges.iter().any(|range| range.contains(&index)) {
+            masked.extend(std::iter::repeat_n('#', character.len_utf16()));
+        } else {
+            masked.push(character);
+        }
+    }
+
+    masked
+}
+
+impl Moderation {
+    #[inline]
+    pub async fn load(path: &Path) -> Result<Self> {
+        Self::from_file(serde_json::from_str(&read_to_string(path).await?)?)
+    }
+
+    #[inline]
+    fn from_file(file: ModerationFile) -> Result<Self> {
+        Ok(Self {
+            block: compile(file.block)?,
+            mask: compile(file.mask)?,
+        })
+    }
+
+    #[inline]
+    pub fn check(&self, text: &str) -> Verdict {
+        if let Some((rule, found)) = self
+            .block
+            .iter()
+            .find_map(|rule| Some((rule, rule.regex.find(text)?)))
+        {
+            return Verdict::Block {
+                reason: format!("matched {}", found.as_str()),
+                rule_id: rule.id.clone(),
+            };
+        }
+
+        let mut rule_id = None;
+        let mut ranges = Vec::new();
+
+        for rule in &self.mask {
+            let before = ranges.len();
+            ranges.extend(rule.regex.find_iter(text).map(|found| found.range()));
+
+            if rule_id.is_none() && ranges.len() > before {
+                rule_id = Some(rule.id.clone());
+            }
+        }
+
+        let Some(rule_id) = rule_id else {
+            return Verdict::Allow;
+        };
+
+        Verdict::Mask {
+            masked: mask(text, &ranges),
+            rule_id,
+        }
+    }
+}
+
+/// Lets moderators know a message was filtered, in the log and in their
+/// channel if there is one.
+#[inline]
+pub fn report(state: &AppState, profile: &LegacyProfile, text: &str, verdict: &Verdict) {
+    let (action, reason) = match verdict {
+        Verdict::Allow => return,
+        Verdict::Mask { .. } => ("masked", None),
+        Verdict::Block { reason, .. } => ("blocked", Some(reason.as_str())),
+    };
+
+    info!(
+        target: "moderation",
+        player = profile.user_display_name,
+        user_id = %profile.user_id,
+        text,
+        reason,
+        "{action} chat message"
+    );
+
+    let Some(channel_id) = state.moderation_log_channel_id else {
+        return;
+    };
+
+    let mut content = format!(
+        "Message from {} was {action}: {}",
+        state.escape_discord(&profile.user_display_name),
+        state.escape_discord(text)
+    );
+
+    if let Some(reason) = reason {
+        content.push_str(&format!(" ({})", state.escape_discord(reason)));
+    }
+
+    let client = state.client.clone();
+
+    spawn(async move {
+        if let Err(e) = client.create_message(channel_id).content(&content).await {
+            warn!(?e, "failed to log filtered message to moderators");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moderation(json: &str) -> Moderation {
+        Moderation::from_file(serde_json::from_str(json).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn ignores_empty_entries() {
+        let moderation = moderation(
+            r#"{
+                "block": { "words": ["", "  ", "badword"], "patterns": [""] },
+                "mask": { "words": [" "], "patterns": ["   "] }
+            }"#,
+        );
+
+        assert_eq!(moderation.check("hello there"), Verdict::Allow);
+        assert!(matches!(
+            moderation.check("a badword here"),
+            Verdict::Block { .. }
+        ));
+    }
+
+    #[test]
+    fn allows_everything_without_rules() {
+        let moderation = moderation(r#"{ "block": { "words": [""] } }"#);
+        assert_eq!(moderation.check("anything at all"), Verdict::Allow);
+    }
+
+    #[test]
+    fn blocks_whole_words_case_insensitively() {
+        let moderation = moderation(r#"{ "block": { "words": [" Bad "] } }"#);
+
+        assert_eq!(
+            moderation.check("that's BAD"),
+            Verdict::Block {
+                reason: "matched BAD".to_string(),
+                rule_id: "words".to_string(),
+            }
+        );
+        assert_eq!(moderation.check("badge"), Verdict::Allow);
+    }
+
+    #[test]
+    fn masks_matches() {
+        let moderation = moderation(r#"{ "mask": { "patterns": ["[0-9]{3}"] } }"#);
+
+        assert_eq!(
+            moderation.check("call 555 now"),
+            Verdict::Mask {
+                masked: "call ### now".to_string(),
+                rule_id: "[0-9]{3}".to_string(),
+            }
+        );
+    }
+}
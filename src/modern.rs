@@ -0,0 +1,165 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    legacy::LegacyProfile,
+    moderation::{Verdict, masked_indices},
+};
+
+/// A chat message from a server with `text-filtering-version=1`. These were
+/// made for Microsoft's player safety service, so they also carry the
+/// `server`, `room`, `area` and `language` it wants, none of which matter to
+/// us.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModernChat {
+    pub user_id: Uuid,
+    pub user_display_name: String,
+    pub data: String,
+}
+
+impl ModernChat {
+    #[inline]
+    pub fn into_parts(self) -> (LegacyProfile, String) {
+        (
+            LegacyProfile {
+                user_display_name: self.user_display_name,
+                user_id: self.user_id,
+            },
+            self.data,
+        )
+    }
+}
+
+/// Why a message was filtered. The server hides the whole message if `id` is
+/// one of the `fullyFilteredEvents` in its filter config.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FilterEvent {
+    pub id: &'static str,
+    pub rule_id: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FilterResult {
+    pub filtered: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<FilterEvent>,
+    /// Which characters to hide, counted in UTF-16 code units.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub redacted_text_index: Vec<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModernChatResponse {
+    pub result: FilterResult,
+}
+
+impl ModernChatResponse {
+    #[inline]
+    pub fn new(text: &str, verdict: Verdict) -> Self {
+        let (filtered, events, redacted_text_index) = match verdict {
+            Verdict::Allow => (false, Vec::new(), Vec::new()),
+            Verdict::Mask { masked, rule_id } => (
+                true,
+                vec![FilterEvent {
+                    id: "mask",
+                    rule_id,
+                }],
+                masked_indices(text, &masked),
+            ),
+            // every character is hidden too, for servers that don't list
+            // `block` as fully filtered
+            Verdict::Block { rule_id, .. } => (
+                true,
+                vec![FilterEvent {
+                    id: "block",
+                    rule_id,
+                }],
+                (0..text.encode_utf16().count()).collect(),
+            ),
+        };
+
+        Self {
+            result: FilterResult {
+                filtered,
+                events,
+                redacted_text_index,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What a 1.21.4 server sends with the filter config from the README.
+    const CHAT_REQUEST: &str = r#"{
+        "userId": "069a79f4-44e9-4726-a5be-fca90e38aaf5",
+        "userDisplayName": "Notch",
+        "server": "",
+        "room": "Java:Chat",
+        "area": "JavaChatRealms",
+        "data": "hello there",
+        "language": "*"
+    }"#;
+
+    #[test]
+    fn reads_chat_request() {
+        let chat: ModernChat = serde_json::from_str(CHAT_REQUEST).unwrap();
+        let (profile, text) = chat.into_parts();
+
+        assert_eq!(profile.user_display_name, "Notch");
+        assert_eq!(
+            profile.user_id.to_string(),
+            "069a79f4-44e9-4726-a5be-fca90e38aaf5"
+        );
+        assert_eq!(text, "hello there");
+    }
+
+    #[test]
+    fn writes_unfiltered_response() {
+        let response = ModernChatResponse::new("hello", Verdict::Allow);
+
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"result":{"filtered":false}}"#
+        );
+    }
+
+    #[test]
+    fn writes_masked_response() {
+        let response = ModernChatResponse::new(
+            "a 😀 bad # word",
+            Verdict::Mask {
+                masked: "a 😀 ### # word".to_string(),
+                rule_id: "words".to_string(),
+            },
+        );
+
+        // the emoji counts twice, and the `#` that was already there isn't
+        // redacted
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"result":{"filtered":true,"events":[{"id":"mask","ruleId":"words"}],"redactedTextIndex":[5,6,7]}}"#
+        );
+    }
+
+    #[test]
+    fn writes_blocked_response() {
+        let response = ModernChatResponse::new(
+            "spam",
+            Verdict::Block {
+                reason: "matched spam".to_string(),
+                rule_id: "sp.m".to_string(),
+            },
+        );
+
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"result":{"filtered":true,"events":[{"id":"block","ruleId":"sp.m"}],"redactedTextIndex":[0,1,2,3]}}"#
+        );
+    }
+}
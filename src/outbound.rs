@@ -0,0 +1,248 @@
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio::{
+    sync::{Notify, oneshot},
+    time::{Instant, sleep, timeout_at},
+};
+use tracing::warn;
+use twilight_http::{api_error::ApiError, error::ErrorType};
+use uuid::Uuid;
+
+use crate::{
+    AppState,
+    content::minecraft_to_markdown,
+    discord::{WebhookBody, execute_webhook},
+};
+
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_CONTENT_LENGTH: usize = 2000;
+
+/// Which message gets dropped when the queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// Drop the message that's been waiting longest, since it's the most out
+    /// of date by the time it would be sent.
+    #[default]
+    DropOldest,
+    DropNewest,
+}
+
+#[derive(Debug)]
+pub struct OutboundMessage {
+    pub sender_name: Cow<'static, str>,
+    pub sender_id: Option<Uuid>,
+    pub body: WebhookBody,
+    /// Told once the message has been sent, or given up on.
+    pub done: Option<oneshot::Sender<()>>,
+}
+
+#[derive(Debug)]
+struct Queue {
+    messages: Mutex<VecDeque<OutboundMessage>>,
+    pushed: Notify,
+    capacity: usize,
+    overflow_policy: OverflowPolicy,
+}
+
+/// Messages waiting to be sent to the webhook. A single worker sends them one
+/// at a time so they show up in Discord in the order they happened.
+#[derive(Debug, Clone)]
+pub struct OutboundQueue {
+    queue: Arc<Queue>,
+}
+
+impl OutboundQueue {
+    #[inline]
+    pub fn new(capacity: usize, overflow_policy: OverflowPolicy) -> Self {
+        Self {
+            queue: Arc::new(Queue {
+                messages: Mutex::new(VecDeque::with_capacity(capacity)),
+                pushed: Notify::new(),
+                capacity: capacity.max(1),
+                overflow_policy,
+            }),
+        }
+    }
+
+    #[inline]
+    pub fn push(&self, message: OutboundMessage) {
+        let mut messages = self.queue.messages.lock().unwrap();
+
+        if messages.len() >= self.queue.capacity {
+            let dropped = match self.queue.overflow_policy {
+                OverflowPolicy::DropOldest => {
+                    let oldest = messages.pop_front();
+                    messages.push_back(message);
+                    oldest
+                }
+                OverflowPolicy::DropNewest => Some(message),
+            };
+
+            warn!(
+                sender = dropped.as_ref().map(|message| message.sender_name.as_ref()),
+                "outbound discord queue is full, dropping a message"
+            );
+        } else {
+            messages.push_back(message);
+        }
+
+        drop(messages);
+        self.queue.pushed.notify_one();
+    }
+
+    /// Takes the next message, but only if `predicate` accepts it.
+    #[inline]
+    fn pop_if(&self, predicate: impl FnOnce(&OutboundMessage) -> bool) -> Option<OutboundMessage> {
+        let mut messages = self.queue.messages.lock().unwrap();
+
+        if messages.front().is_some_and(predicate) {
+            messages.pop_front()
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.queue.messages.lock().unwrap().is_empty()
+    }
+
+    #[inline]
+    async fn pop(&self) -> OutboundMessage {
+        loop {
+            if let Some(message) = self.queue.messages.lock().unwrap().pop_front() {
+                return message;
+            }
+
+            self.queue.pushed.notified().await;
+        }
+    }
+}
+
+/// How long to wait before trying a failed message again, if it's worth
+/// trying again at all.
+#[inline]
+fn retry_delay(e: &twilight_http::Error, attempt: u32) -> Option<Duration> {
+    let backoff = INITIAL_RETRY_DELAY * 2u32.pow(attempt);
+
+    match e.kind() {
+        ErrorType::Response {
+            error: ApiError::Ratelimited(ratelimited),
+            ..
+        } => Some(Duration::from_secs_f64(ratelimited.retry_after.max(0.0))),
+        ErrorType::Response { status, .. } if status.is_server_error() => Some(backoff),
+        // an error response without a JSON body, usually a gateway error from
+        // somewhere in front of Discord
+        ErrorType::Parsing { .. }
+        | ErrorType::ServiceUnavailable { .. }
+        | ErrorType::RequestError
+        | ErrorType::RequestTimedOut
+        | ErrorType::ChunkingResponse => Some(backoff),
+        _ => None,
+    }
+}
+
+/// Appends `next` to `message` if they're both text from the same sender and
+/// the result still fits in one message.
+#[inline]
+fn merge(state: &AppState, message: &mut OutboundMessage, next: &OutboundMessage) -> bool {
+    if message.sender_name != next.sender_name || message.sender_id != next.sender_id {
+        return false;
+    }
+
+    let (WebhookBody::Text(content), WebhookBody::Text(next_content)) =
+        (&mut message.body, &next.body)
+    else {
+        return false;
+    };
+
+    let merged = format!("{content}\n{next_content}");
+
+    let formatted = minecraft_to_markdown(&merged, |text| state.escape_discord(text));
+
+    if formatted.chars().count() > MAX_CONTENT_LENGTH {
+        return false;
+    }
+
+    *content = merged;
+    true
+}
+
+/// Waits up to `window` for more messages from the same sender to send along
+/// with `message`. Anything else coming in ends the batch early, so nothing
+/// gets sent out of order.
+#[inline]
+async fn collect_batch(
+    state: &AppState,
+    message: &mut OutboundMessage,
+    done: &mut Vec<oneshot::Sender<()>>,
+    window: Duration,
+) {
+    let deadline = Instant::now() + window;
+
+    loop {
+        while let Some(mut next) = state.outbound.pop_if(|next| merge(state, message, next)) {
+            done.extend(next.done.take());
+        }
+
+        if !state.outbound.is_empty() {
+            return;
+        }
+
+        if timeout_at(deadline, state.outbound.queue.pushed.notified())
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Sends queued messages for as long as the bridge runs.
+#[inline]
+pub async fn deliver_outbound(state: AppState, batch_window: Option<Duration>) {
+    loop {
+        let mut message = state.outbound.pop().await;
+        let mut done = Vec::from_iter(message.done.take());
+
+        if let Some(window) = batch_window {
+            collect_batch(&state, &mut message, &mut done, window).await;
+        }
+
+        for attempt in 0..MAX_ATTEMPTS {
+            let Err(e) = execute_webhook(
+                &state,
+                &message.sender_name,
+                message.sender_id,
+                &message.body,
+            )
+            .await
+            else {
+                break;
+            };
+
+            match retry_delay(&e, attempt) {
+                Some(delay) if attempt + 1 < MAX_ATTEMPTS => {
+                    warn!(?e, ?delay, "failure sending message to webhook, retrying");
+                    sleep(delay).await;
+                }
+                _ => {
+                    warn!(?e, "failure sending message to webhook, giving up");
+                    break;
+                }
+            }
+        }
+
+        for done in done {
+            let _ = done.send(());
+        }
+    }
+}
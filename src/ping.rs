@@ -0,0 +1,467 @@
+use std::time::Duration;
+
+use anyhow::{Result, anyhow, bail};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{Instant, interval, timeout},
+};
+use tracing::{info, warn};
+
+use crate::{
+    AppState, content::strip_minecraft_formatting, presence::set_server_online, query::FullStat,
+};
+
+const DEFAULT_PORT: u16 = 25565;
+/// Any protocol version works for a status request, the server just echoes
+/// its own back to us.
+const PROTOCOL_VERSION: i32 = -1;
+const STATUS_STATE: i32 = 1;
+const HANDSHAKE_ID: i32 = 0x00;
+const STATUS_REQUEST_ID: i32 = 0x00;
+const STATUS_RESPONSE_ID: i32 = 0x00;
+const PING_ID: i32 = 0x01;
+const PONG_ID: i32 = 0x01;
+/// Generous, since the response carries the server icon.
+const MAX_PACKET_LENGTH: usize = 1 << 21;
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Version {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SamplePlayer {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Players {
+    pub max: usize,
+    pub online: usize,
+    #[serde(default)]
+    pub sample: Vec<SamplePlayer>,
+}
+
+/// What the server shows in the multiplayer menu.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerStatus {
+    pub version: Version,
+    pub players: Players,
+    #[serde(default)]
+    pub description: Value,
+    #[serde(skip)]
+    pub latency: Duration,
+}
+
+impl ServerStatus {
+    /// The MOTD as plain text, without any formatting.
+    #[inline]
+    pub fn motd(&self) -> String {
+        let mut motd = String::new();
+        flatten_component(&self.description, &mut motd);
+
+        strip_minecraft_formatting(&motd)
+    }
+}
+
+#[inline]
+fn flatten_component(component: &Value, into: &mut String) {
+    match component {
+        Value::String(text) => into.push_str(text),
+        Value::Array(components) => {
+            for component in components {
+                flatten_component(component, into);
+            }
+        }
+        Value::Object(object) => {
+            if let Some(text) = object.get("text") {
+                flatten_component(text, into);
+            }
+
+            if let Some(extra) = object.get("extra") {
+                flatten_component(extra, into);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[inline]
+fn write_var_int(buffer: &mut Vec<u8>, value: i32) {
+    let mut value = value.cast_unsigned();
+
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            buffer.push(byte);
+            return;
+        }
+
+        buffer.push(byte | 0x80);
+    }
+}
+
+#[inline]
+async fn read_var_int<R: AsyncRead + Unpin>(from: &mut R) -> Result<i32> {
+    let mut value = 0u32;
+
+    for position in 0..5 {
+        let byte = from.read_u8().await?;
+        value |= u32::from(byte & 0x7F) << (position * 7);
+
+        if byte & 0x80 == 0 {
+            return Ok(value.cast_signed());
+        }
+    }
+
+    bail!("var int is too long")
+}
+
+#[inline]
+fn write_string(buffer: &mut Vec<u8>, string: &str) -> Result<()> {
+    write_var_int(buffer, i32::try_from(string.len())?);
+    buffer.extend_from_slice(string.as_bytes());
+    Ok(())
+}
+
+#[inline]
+async fn write_packet(stream: &mut TcpStream, id: i32, body: &[u8]) -> Result<()> {
+    let mut payload = Vec::with_capacity(body.len() + 5);
+    write_var_int(&mut payload, id);
+    payload.extend_from_slice(body);
+
+    let mut packet = Vec::with_capacity(payload.len() + 5);
+    write_var_int(&mut packet, i32::try_from(payload.len())?);
+    packet.extend_from_slice(&payload);
+
+    stream.write_all(&packet).await?;
+    Ok(())
+}
+
+/// Reads a packet, returning its ID and the rest of its body.
+#[inline]
+async fn read_packet(stream: &mut TcpStream) -> Result<(i32, Vec<u8>)> {
+    let length = usize::try_from(read_var_int(stream).await?)?;
+
+    if length > MAX_PACKET_LENGTH {
+        bail!("packet too long: {length} bytes");
+    }
+
+    let mut payload = vec![0; length];
+    stream.read_exact(&mut payload).await?;
+
+    let mut body = payload.as_slice();
+    let id = read_var_int(&mut body).await?;
+
+    Ok((id, body.to_vec()))
+}
+
+#[inline]
+fn split_address(address: &str) -> Result<(&str, u16)> {
+    match address.rsplit_once(':') {
+        Some((host, port)) => Ok((
+            host.trim_start_matches('[').trim_end_matches(']'),
+            port.parse()?,
+        )),
+        None => Ok((address, DEFAULT_PORT)),
+    }
+}
+
+#[inline]
+async fn exchange(address: &str) -> Result<ServerStatus> {
+    let (host, port) = split_address(address)?;
+    let mut stream = TcpStream::connect((host, port)).await?;
+
+    let mut handshake = Vec::new();
+    write_var_int(&mut handshake, PROTOCOL_VERSION);
+    write_string(&mut handshake, host)?;
+    handshake.extend_from_slice(&port.to_be_bytes());
+    write_var_int(&mut handshake, STATUS_STATE);
+
+    write_packet(&mut stream, HANDSHAKE_ID, &handshake).await?;
+    write_packet(&mut stream, STATUS_REQUEST_ID, &[]).await?;
+
+    let (id, body) = read_packet(&mut stream).await?;
+
+    if id != STATUS_RESPONSE_ID {
+        bail!("expected a status response, got packet {id:#04x}");
+    }
+
+    let mut body = body.as_slice();
+    let json_length = usize::try_from(read_var_int(&mut body).await?)?;
+    let json = body
+        .get(..json_length)
+        .ok_or_else(|| anyhow!("status response is truncated"))?;
+    let mut status: ServerStatus = serde_json::from_slice(json)?;
+
+    let sent_at = Instant::now();
+    write_packet(&mut stream, PING_ID, &0i64.to_be_bytes()).await?;
+
+    // older servers just hang up instead of answering the ping
+    if let Ok((PONG_ID, _)) = read_packet(&mut stream).await {
+        status.latency = sent_at.elapsed();
+    }
+
+    Ok(status)
+}
+
+/// Asks the server at `address` for its status the same way the multiplayer
+/// menu does. This works whether or not we're the ones running the server.
+#[inline]
+pub async fn ping(address: &str) -> Result<ServerStatus> {
+    timeout(PING_TIMEOUT, exchange(address))
+        .await
+        .map_err(|_| anyhow!("timed out pinging the server"))?
+}
+
+/// Describes the server's status, with whatever extra it told us over the
+/// query protocol.
+#[inline]
+pub fn describe_status(
+    state: &AppState,
+    status: &ServerStatus,
+    full_stat: Option<&FullStat>,
+) -> String {
+    let mut lines = vec![
+        format!("**{}**", state.escape_discord(status.motd().trim())),
+        format!(
+            "{} ({}ms)",
+            state.escape_discord(&status.version.name),
+            status.latency.as_millis()
+        ),
+        format!(
+            "{}/{} players online",
+            status.players.online, status.players.max
+        ),
+    ];
+
+    if !status.players.sample.is_empty() {
+        let names = status
+            .players
+            .sample
+            .iter()
+            .map(|player| state.escape_discord(&player.name))
+            .collect::<Vec<_>>()
+            .join(", ");
+        lines.push(names);
+    }
+
+    if let Some(full_stat) = full_stat {
+        lines.push(format!("Map: {}", state.escape_discord(&full_stat.map)));
+
+        if !full_stat.plugins.is_empty() {
+            lines.push(format!(
+                "Plugins: {}",
+                state.escape_discord(&full_stat.plugins)
+            ));
+        }
+    }
+
+    lines.join("\n")
+}
+
+/// Pings the server every so often, marking it offline when it stops
+/// answering. This is the only way we notice it going down when we aren't
+/// wrapping it or reading its log.
+#[inline]
+pub async fn check_health(state: AppState, address: String, period: Duration) -> Result<()> {
+    let mut ticker = interval(period);
+    let mut healthy = None;
+
+    loop {
+        ticker.tick().await;
+
+        let res = ping(&address).await;
+
+        if let Ok(status) = &res {
+            state.presence.send_if_modified(|presence| {
+                let modified = presence.max != Some(status.players.max);
+                presence.max = Some(status.players.max);
+                modified
+            });
+        }
+
+        set_server_online(&state, res.is_ok());
+
+        match (res, healthy) {
+            (Ok(_), Some(false) | None) => {
+                info!(address, "server is answering pings");
+                healthy = Some(true);
+            }
+            (Err(e), Some(true) | None) => {
+                warn!(?e, address, "server isn't answering pings");
+                healthy = Some(false);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const STATUS_JSON: &str = r#"{
+        "version": { "name": "1.21.4", "protocol": 769 },
+        "players": {
+            "max": 20,
+            "online": 2,
+            "sample": [
+                { "name": "Notch", "id": "069a79f4-44e9-4726-a5be-fca90e38aaf5" },
+                { "name": "jeb_", "id": "853c80ef-3c37-49fd-aa49-938b674adae6" }
+            ]
+        },
+        "description": { "text": "§aA ", "extra": ["Minecraft ", { "text": "Server" }] }
+    }"#;
+
+    /// Stands in for a server, answering a single status request and ping.
+    async fn serve_status(json: &'static str, answer_ping: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let (id, mut handshake) = read_packet(&mut stream).await.unwrap();
+            assert_eq!(id, HANDSHAKE_ID);
+            assert_eq!(handshake.pop(), Some(1));
+
+            let (id, body) = read_packet(&mut stream).await.unwrap();
+            assert_eq!(id, STATUS_REQUEST_ID);
+            assert!(body.is_empty());
+
+            let mut response = Vec::new();
+            write_string(&mut response, json).unwrap();
+            write_packet(&mut stream, STATUS_RESPONSE_ID, &response)
+                .await
+                .unwrap();
+
+            let (id, payload) = read_packet(&mut stream).await.unwrap();
+            assert_eq!(id, PING_ID);
+
+            if answer_ping {
+                write_packet(&mut stream, PONG_ID, &payload).await.unwrap();
+            }
+        });
+
+        address
+    }
+
+    #[tokio::test]
+    async fn var_int_round_trips() {
+        for value in [
+            0,
+            1,
+            127,
+            128,
+            255,
+            25565,
+            2_097_151,
+            i32::MAX,
+            -1,
+            i32::MIN,
+        ] {
+            let mut buffer = Vec::new();
+            write_var_int(&mut buffer, value);
+
+            let decoded = read_var_int(&mut buffer.as_slice()).await.unwrap();
+            assert_eq!(decoded, value);
+        }
+    }
+
+    #[test]
+    fn var_int_encoding_matches_protocol() {
+        let mut buffer = Vec::new();
+        write_var_int(&mut buffer, 25565);
+        assert_eq!(buffer, [0xDD, 0xC7, 0x01]);
+
+        buffer.clear();
+        write_var_int(&mut buffer, -1);
+        assert_eq!(buffer, [0xFF, 0xFF, 0xFF, 0xFF, 0x0F]);
+    }
+
+    #[tokio::test]
+    async fn rejects_overlong_var_int() {
+        let bytes = [0xFF; 6];
+        assert!(read_var_int(&mut bytes.as_slice()).await.is_err());
+    }
+
+    #[test]
+    fn splits_addresses() {
+        assert_eq!(
+            split_address("example.com").unwrap(),
+            ("example.com", DEFAULT_PORT)
+        );
+        assert_eq!(
+            split_address("127.0.0.1:25566").unwrap(),
+            ("127.0.0.1", 25566)
+        );
+        assert_eq!(split_address("[::1]:25565").unwrap(), ("::1", 25565));
+        assert!(split_address("example.com:nope").is_err());
+    }
+
+    #[tokio::test]
+    async fn pings_stand_in_server() {
+        let address = serve_status(STATUS_JSON, true).await;
+        let status = ping(&address).await.unwrap();
+
+        assert_eq!(status.version.name, "1.21.4");
+        assert_eq!(status.players.online, 2);
+        assert_eq!(status.players.max, 20);
+        assert_eq!(
+            status
+                .players
+                .sample
+                .iter()
+                .map(|player| player.name.as_str())
+                .collect::<Vec<_>>(),
+            ["Notch", "jeb_"]
+        );
+        assert_eq!(status.motd(), "A Minecraft Server");
+    }
+
+    #[tokio::test]
+    async fn tolerates_server_ignoring_ping() {
+        let json = r#"{"version":{"name":"Paper 1.8.8","protocol":47},"players":{"max":5,"online":0},"description":"hi"}"#;
+        let address = serve_status(json, false).await;
+        let status = ping(&address).await.unwrap();
+
+        assert_eq!(status.players.online, 0);
+        assert!(status.players.sample.is_empty());
+        assert_eq!(status.motd(), "hi");
+        assert_eq!(status.latency, Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn fails_on_garbage() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        assert!(ping(&address).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fails_when_nobody_is_listening() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        assert!(ping(&address).await.is_err());
+    }
+}
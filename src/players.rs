@@ -0,0 +1,99 @@
+use std::sync::LazyLock;
+
+use anyhow::anyhow;
+use regex::Regex;
+use tracing::warn;
+
+use crate::{AppState, console::Console, query::query};
+
+static LIST_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?s)There are (?P<online>\d+)(?:/| of a max(?: of)? )(?P<max>\d+) players online:(?P<names>.*)",
+    )
+    .unwrap()
+});
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerList {
+    pub online: usize,
+    pub max: Option<usize>,
+    pub names: Vec<String>,
+}
+
+impl PlayerList {
+    /// Parses the output of the `list` command.
+    #[inline]
+    pub fn parse(output: &str) -> Option<Self> {
+        let captures = LIST_REGEX.captures(output)?;
+
+        Some(Self {
+            online: captures["online"].parse().ok()?,
+            max: captures["max"].parse().ok(),
+            names: captures["names"]
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(ToString::to_string)
+                .collect(),
+        })
+    }
+}
+
+/// Asks the server who's online over RCON or the query protocol, since that
+/// also tells us the player limit. Otherwise, or if that fails, we go by the
+/// join and leave events the server has sent us.
+#[inline]
+pub async fn list_players(state: &AppState) -> PlayerList {
+    if let Console::Rcon(rcon_client) = &state.console {
+        let res = rcon_client.execute("list").await.and_then(|output| {
+            PlayerList::parse(&output).ok_or_else(|| anyhow!("unexpected list output {output:?}"))
+        });
+
+        match res {
+            Ok(player_list) => return player_list,
+            Err(e) => warn!(?e, "failed to list players over rcon"),
+        }
+    }
+
+    if let Some(query_address) = &state.query_address {
+        match query(query_address).await {
+            Ok(full_stat) => {
+                return PlayerList {
+                    online: full_stat.online,
+                    max: Some(full_stat.max),
+                    names: full_stat.players,
+                };
+            }
+            Err(e) => warn!(?e, "failed to query players"),
+        }
+    }
+
+    let names = state.roster.names();
+
+    PlayerList {
+        online: names.len(),
+        max: None,
+        names,
+    }
+}
+
+#[inline]
+pub fn describe_player_list(state: &AppState, player_list: &PlayerList) -> String {
+    let count = match player_list.max {
+        Some(max) => format!("{}/{max}", player_list.online),
+        None => player_list.online.to_string(),
+    };
+
+    if player_list.names.is_empty() {
+        return format!("Nobody is online ({count})");
+    }
+
+    let names = player_list
+        .names
+        .iter()
+        .map(|name| state.formatting_regex.replace_all(name, "\\$1"))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!("**{count}** players online: {names}")
+}
@@ -0,0 +1,101 @@
+use anyhow::Result;
+use tokio::time::Instant;
+use twilight_model::gateway::{
+    payload::outgoing::UpdatePresence,
+    presence::{Activity, ActivityType, MinimalActivity, Status},
+};
+
+use crate::{AppState, players::PlayerList};
+
+/// What the bot's status says about the server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Presence {
+    /// When the server last came up, if it's up.
+    pub online_since: Option<Instant>,
+    pub players: usize,
+    pub max: Option<usize>,
+}
+
+impl Presence {
+    #[inline]
+    pub const fn is_online(&self) -> bool {
+        self.online_since.is_some()
+    }
+
+    #[inline]
+    pub fn to_update(self) -> Result<UpdatePresence> {
+        if !self.is_online() {
+            let mut activity: Activity = MinimalActivity {
+                kind: ActivityType::Custom,
+                name: "Custom Status".to_string(),
+                url: None,
+            }
+            .into();
+            activity.state = Some("Server offline".to_string());
+
+            return Ok(UpdatePresence::new([activity], false, None, Status::Idle)?);
+        }
+
+        let name = match self.max {
+            Some(max) => format!("{}/{max} players", self.players),
+            None if self.players == 1 => "1 player".to_string(),
+            None => format!("{} players", self.players),
+        };
+
+        let activity = MinimalActivity {
+            kind: ActivityType::Watching,
+            name,
+            url: None,
+        };
+
+        Ok(UpdatePresence::new(
+            [activity.into()],
+            false,
+            None,
+            Status::Online,
+        )?)
+    }
+}
+
+#[inline]
+pub fn set_server_online(state: &AppState, online: bool) {
+    state.presence.send_if_modified(|presence| {
+        if presence.is_online() == online {
+            return false;
+        }
+
+        presence.online_since = online.then(Instant::now);
+        true
+    });
+}
+
+/// Called whenever the roster changes.
+#[inline]
+pub fn refresh_player_count(state: &AppState) {
+    let players = state.roster.len();
+
+    state.presence.send_if_modified(|presence| {
+        let modified = presence.players != players;
+        presence.players = players;
+        modified
+    });
+}
+
+/// The player limit only shows up in the output of `list`, so we ask for it
+/// once the server is up.
+#[inline]
+pub async fn refresh_max_players(state: AppState) {
+    let Ok(output) = state.console.execute("list").await else {
+        return;
+    };
+
+    let Some(max) = PlayerList::parse(&output).and_then(|player_list| player_list.max) else {
+        return;
+    };
+
+    state.presence.send_if_modified(|presence| {
+        let modified = presence.max != Some(max);
+        presence.max = Some(max);
+        modified
+    });
+}
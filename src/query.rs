@@ -0,0 +1,343 @@
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use anyhow::{Result, anyhow, bail};
+use tokio::{
+    net::{UdpSocket, lookup_host},
+    time::timeout,
+};
+
+const MAGIC: [u8; 2] = [0xFE, 0xFD];
+const HANDSHAKE: u8 = 0x09;
+const STAT: u8 = 0x00;
+/// We use a fresh socket for every query, so there's nothing to tell apart.
+const SESSION_ID: i32 = 1;
+/// Fixed padding the server puts before the key/value section of a full stat.
+const KV_PADDING: &[u8] = b"splitnum\0\x80\0";
+/// Fixed padding the server puts before the player list.
+const PLAYER_PADDING: &[u8] = b"\x01player_\0\0";
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a full stat request tells us that a server list ping doesn't.
+#[derive(Debug, Clone)]
+pub struct FullStat {
+    /// Only filled in by servers that change it, vanilla leaves it empty.
+    pub plugins: String,
+    pub map: String,
+    pub online: usize,
+    pub max: usize,
+    pub players: Vec<String>,
+}
+
+#[inline]
+fn request(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(7 + payload.len());
+    packet.extend_from_slice(&MAGIC);
+    packet.push(kind);
+    packet.extend_from_slice(&SESSION_ID.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Checks the type and session ID a response starts with and returns the rest.
+#[inline]
+fn response_body(response: &[u8], kind: u8) -> Result<&[u8]> {
+    let (header, body) = response
+        .split_at_checked(5)
+        .ok_or_else(|| anyhow!("query response is too short"))?;
+
+    if header[0] != kind || header[1..] != SESSION_ID.to_be_bytes() {
+        bail!("unexpected query response {header:02x?}");
+    }
+
+    Ok(body)
+}
+
+/// Splits off a null terminated string.
+#[inline]
+fn read_string(bytes: &mut &[u8]) -> Result<String> {
+    let end = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .ok_or_else(|| anyhow!("unterminated string in query response"))?;
+
+    let string = String::from_utf8_lossy(&bytes[..end]).into_owned();
+    *bytes = &bytes[end + 1..];
+
+    Ok(string)
+}
+
+#[inline]
+fn parse_full_stat(body: &[u8]) -> Result<FullStat> {
+    let mut body = body
+        .strip_prefix(KV_PADDING)
+        .ok_or_else(|| anyhow!("full stat is missing its padding"))?;
+
+    let mut values = HashMap::new();
+
+    loop {
+        let key = read_string(&mut body)?;
+
+        if key.is_empty() {
+            break;
+        }
+
+        values.insert(key, read_string(&mut body)?);
+    }
+
+    let mut body = body
+        .strip_prefix(PLAYER_PADDING)
+        .ok_or_else(|| anyhow!("full stat is missing its player list"))?;
+
+    let mut players = Vec::new();
+
+    loop {
+        let player = read_string(&mut body)?;
+
+        if player.is_empty() {
+            break;
+        }
+
+        players.push(player);
+    }
+
+    let mut take = |key| values.remove(key).unwrap_or_default();
+
+    Ok(FullStat {
+        plugins: take("plugins"),
+        map: take("map"),
+        online: take("numplayers").parse()?,
+        max: take("maxplayers").parse()?,
+        players,
+    })
+}
+
+#[inline]
+async fn exchange(socket: &UdpSocket, packet: &[u8], kind: u8) -> Result<Vec<u8>> {
+    socket.send(packet).await?;
+
+    let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+    let length = socket.recv(&mut buffer).await?;
+    buffer.truncate(length);
+
+    Ok(response_body(&buffer, kind)?.to_vec())
+}
+
+#[inline]
+async fn full_stat(address: &str) -> Result<FullStat> {
+    let target = lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow!("{address} didn't resolve to anything"))?;
+
+    let local: SocketAddr = if target.is_ipv4() {
+        "0.0.0.0:0".parse()?
+    } else {
+        "[::]:0".parse()?
+    };
+
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(target).await?;
+
+    let handshake = exchange(&socket, &request(HANDSHAKE, &[]), HANDSHAKE).await?;
+    let challenge: i32 = read_string(&mut handshake.as_slice())?.parse()?;
+
+    let mut payload = challenge.to_be_bytes().to_vec();
+    // asking for a full stat rather than a basic one
+    payload.extend_from_slice(&[0; 4]);
+
+    let body = exchange(&socket, &request(STAT, &payload), STAT).await?;
+    parse_full_stat(&body)
+}
+
+/// Asks the server for everything it shares over the query protocol, which
+/// it only answers with `enable-query=true` in server.properties. Unlike RCON
+/// this doesn't need a password.
+#[inline]
+pub async fn query(address: &str) -> Result<FullStat> {
+    timeout(QUERY_TIMEOUT, full_stat(address))
+        .await
+        .map_err(|_| anyhow!("timed out querying the server"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHALLENGE: i32 = -9_513_307;
+
+    /// A full stat body laid out the way the vanilla server does it.
+    fn full_stat_body(players: &[&str]) -> Vec<u8> {
+        let mut body = KV_PADDING.to_vec();
+
+        for (key, value) in [
+            ("hostname", "A Minecraft Server"),
+            ("gametype", "SMP"),
+            ("game_id", "MINECRAFT"),
+            ("version", "1.21.4"),
+            ("plugins", ""),
+            ("map", "world"),
+            ("numplayers", &players.len().to_string()),
+            ("maxplayers", "20"),
+            ("hostport", "25565"),
+            ("hostip", "127.0.0.1"),
+        ] {
+            body.extend_from_slice(key.as_bytes());
+            body.push(0);
+            body.extend_from_slice(value.as_bytes());
+            body.push(0);
+        }
+
+        body.push(0);
+        body.extend_from_slice(PLAYER_PADDING);
+
+        for player in players {
+            body.extend_from_slice(player.as_bytes());
+            body.push(0);
+        }
+
+        body.push(0);
+        body
+    }
+
+    fn response(kind: u8, body: &[u8]) -> Vec<u8> {
+        let mut response = vec![kind];
+        response.extend_from_slice(&SESSION_ID.to_be_bytes());
+        response.extend_from_slice(body);
+        response
+    }
+
+    /// Stands in for a server with query enabled, answering one handshake
+    /// and one full stat request, or `stat_reply` in place of the full stat
+    /// if given.
+    async fn serve_query(stat_reply: Option<Vec<u8>>) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap().to_string();
+
+        tokio::spawn(async move {
+            let mut buffer = [0; 1500];
+
+            let (length, peer) = socket.recv_from(&mut buffer).await.unwrap();
+            assert_eq!(&buffer[..length], request(HANDSHAKE, &[]));
+
+            let token = format!("{CHALLENGE}\0");
+            socket
+                .send_to(&response(HANDSHAKE, token.as_bytes()), peer)
+                .await
+                .unwrap();
+
+            let (length, peer) = socket.recv_from(&mut buffer).await.unwrap();
+            let mut payload = CHALLENGE.to_be_bytes().to_vec();
+            payload.extend_from_slice(&[0; 4]);
+            assert_eq!(&buffer[..length], request(STAT, &payload));
+
+            let reply =
+                stat_reply.unwrap_or_else(|| response(STAT, &full_stat_body(&["Notch", "jeb_"])));
+            socket.send_to(&reply, peer).await.unwrap();
+        });
+
+        address
+    }
+
+    #[test]
+    fn builds_requests() {
+        assert_eq!(
+            request(HANDSHAKE, &[]),
+            [0xFE, 0xFD, 0x09, 0x00, 0x00, 0x00, 0x01]
+        );
+        assert_eq!(
+            request(STAT, &[1, 2, 3, 4]),
+            [0xFE, 0xFD, 0x00, 0x00, 0x00, 0x00, 0x01, 1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn checks_response_headers() {
+        let handshake = response(HANDSHAKE, b"123\0");
+
+        assert_eq!(response_body(&handshake, HANDSHAKE).unwrap(), b"123\0");
+        assert!(response_body(&handshake, STAT).is_err());
+        assert!(response_body(&[HANDSHAKE, 0, 0, 0, 2, b'1', 0], HANDSHAKE).is_err());
+        assert!(response_body(&[HANDSHAKE, 0, 0], HANDSHAKE).is_err());
+        assert!(response_body(&[], HANDSHAKE).is_err());
+    }
+
+    #[test]
+    fn reads_challenge_tokens() {
+        let mut body = b"-9513307\0".as_slice();
+        assert_eq!(
+            read_string(&mut body).unwrap().parse::<i32>().unwrap(),
+            CHALLENGE
+        );
+        assert!(body.is_empty());
+
+        assert!(read_string(&mut b"9513307".as_slice()).is_err());
+    }
+
+    #[test]
+    fn parses_full_stat() {
+        let stat = parse_full_stat(&full_stat_body(&["Notch", "jeb_"])).unwrap();
+
+        assert_eq!(stat.plugins, "");
+        assert_eq!(stat.map, "world");
+        assert_eq!(stat.online, 2);
+        assert_eq!(stat.max, 20);
+        assert_eq!(stat.players, ["Notch", "jeb_"]);
+    }
+
+    #[test]
+    fn parses_empty_player_list() {
+        let stat = parse_full_stat(&full_stat_body(&[])).unwrap();
+
+        assert_eq!(stat.online, 0);
+        assert!(stat.players.is_empty());
+    }
+
+    #[test]
+    fn rejects_truncated_full_stat() {
+        let body = full_stat_body(&["Notch", "jeb_"]);
+
+        // every cut short of the whole thing is missing a terminator at least
+        for length in 0..body.len() {
+            assert!(parse_full_stat(&body[..length]).is_err(), "{length}");
+        }
+    }
+
+    #[test]
+    fn rejects_garbage_full_stat() {
+        assert!(parse_full_stat(b"not a full stat at all\0\0").is_err());
+
+        let mut missing_player_padding = full_stat_body(&[]);
+        let padding_start = missing_player_padding.len() - PLAYER_PADDING.len() - 1;
+        missing_player_padding[padding_start] = b'x';
+        assert!(parse_full_stat(&missing_player_padding).is_err());
+
+        let mut not_a_number = full_stat_body(&[]);
+        let count = not_a_number
+            .windows(11)
+            .position(|window| window == b"numplayers\0")
+            .unwrap();
+        not_a_number[count + 11] = b'x';
+        assert!(parse_full_stat(&not_a_number).is_err());
+    }
+
+    #[tokio::test]
+    async fn queries_stand_in_server() {
+        let address = serve_query(None).await;
+        let stat = query(&address).await.unwrap();
+
+        assert_eq!(stat.map, "world");
+        assert_eq!(stat.online, 2);
+        assert_eq!(stat.max, 20);
+        assert_eq!(stat.players, ["Notch", "jeb_"]);
+    }
+
+    #[tokio::test]
+    async fn rejects_garbage_reply() {
+        let address = serve_query(Some(b"\xFF\xFF garbage".to_vec())).await;
+        assert!(query(&address).await.is_err());
+
+        let address = serve_query(Some(response(STAT, b"splitnum"))).await;
+        assert!(query(&address).await.is_err());
+    }
+}
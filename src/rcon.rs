@@ -1,26 +1,44 @@
-use std::borrow::Cow;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use anyhow::{Result, bail};
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter, copy},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
     net::{TcpStream, tcp::OwnedWriteHalf},
     select, spawn,
-    sync::{mpsc::UnboundedReceiver, oneshot},
+    sync::{
+        mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+        oneshot,
+    },
+    time::{Instant, sleep_until, timeout},
 };
 use tokio_util::task::AbortOnDropHandle;
-use tracing::info;
+use tracing::{debug, info, warn};
 
-use crate::discord::IncomingDiscordMessage;
+use crate::{component::Tellraw, discord::IncomingDiscordMessage, events::GameEvent};
 
 const LOGIN: i32 = 3;
 const COMMAND: i32 = 2;
+/// The server answers anything that isn't a login or a command with a single
+/// packet, and handles packets in order, so sending one of these after a
+/// command tells us when we've seen every packet of that command's response.
+const RESPONSE_VALUE: i32 = 0;
+/// How long the server has to answer a packet before we assume the
+/// connection is stuck.
+const ACK_TIMEOUT: Duration = Duration::from_secs(30);
 
-#[inline]
-async fn skip_int<R: AsyncRead + Unpin>(mut reader: R) -> Result<()> {
-    copy(&mut (&mut reader).take(4), &mut tokio::io::sink()).await?;
-
-    Ok(())
-}
+/// Request ID, type and the two trailing nul bytes.
+const MIN_PACKET_LENGTH: usize = 10;
+/// The server splits responses into 4096 byte chunks, but re-encoding a chunk
+/// that was cut in the middle of a character can make it a little longer.
+const MAX_PACKET_LENGTH: usize = 4096 * 3 + MIN_PACKET_LENGTH;
+/// The server reads each packet with a single read of at most 1460 bytes,
+/// length included, and drops the connection if the packet was any longer.
+pub const MAX_COMMAND_LENGTH: usize = 1460 - 4 - MIN_PACKET_LENGTH;
 
 struct Packet<'a> {
     pub request_id: i32,
@@ -31,32 +49,24 @@ struct Packet<'a> {
 impl<'a> Packet<'a> {
     #[inline]
     pub async fn read<R: AsyncRead + Unpin>(mut reader: R) -> Result<Self> {
-        skip_int(&mut reader).await?;
+        let length = usize::try_from(reader.read_i32_le().await?)?;
+
+        if !(MIN_PACKET_LENGTH..=MAX_PACKET_LENGTH).contains(&length) {
+            bail!("invalid packet length {length}");
+        }
 
         let request_id = reader.read_i32_le().await?;
         let request_type = reader.read_i32_le().await?;
-        let mut payload_body = Vec::new();
+        let mut payload_body = vec![0; length - MIN_PACKET_LENGTH];
+        reader.read_exact(&mut payload_body).await?;
 
-        loop {
-            let byte = reader.read_u8().await?;
-
-            if byte == b'\0' {
-                break;
-            }
-
-            if payload_body.len() == 4096 {
-                bail!("payload body too large");
-            }
-
-            payload_body.push(byte);
-        }
-
-        reader.read_u8().await?; // additional padding? what?
+        // nul terminator and extra byte of padding
+        reader.read_u16().await?;
 
         Ok(Self {
             request_id,
             request_type,
-            payload: Cow::Owned(String::from_utf8(payload_body)?),
+            payload: Cow::Owned(String::from_utf8_lossy(&payload_body).into_owned()),
         })
     }
 
@@ -76,17 +86,78 @@ impl<'a> Packet<'a> {
     }
 }
 
+/// How many Discord messages we hold on to while the server is unreachable.
+/// Anything older than this is dropped, since nobody wants to read half an
+/// hour of backlog once the server comes back.
+const MAX_BACKLOG: usize = 100;
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+const EXECUTE_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[derive(Debug)]
-pub struct RconClient {
+struct RconRequest {
+    command: String,
+    reply: Option<oneshot::Sender<Result<String>>>,
+}
+
+impl RconRequest {
+    /// Whether anybody still cares about the response. Commands we've given
+    /// up waiting on aren't worth running late.
+    #[inline]
+    fn is_abandoned(&self) -> bool {
+        self.reply.as_ref().is_some_and(|reply| reply.is_closed())
+    }
+}
+
+#[derive(Debug)]
+struct PendingResponse {
+    output: String,
+    reply: oneshot::Sender<Result<String>>,
+}
+
+type PendingResponses = Arc<Mutex<HashMap<i32, PendingResponse>>>;
+
+/// Reads responses, handing them to whoever's waiting on them and telling the
+/// writer which request each one answered.
+#[inline]
+async fn read_responses<R: AsyncRead + Unpin>(
+    mut reader: R,
+    pending: PendingResponses,
+    ack_sender: UnboundedSender<i32>,
+) -> Result<()> {
+    loop {
+        let packet = Packet::read(&mut reader).await?;
+        let _ = ack_sender.send(packet.request_id);
+        let mut pending = pending.lock().unwrap();
+
+        if let Some(response) = pending.get_mut(&packet.request_id) {
+            response.output.push_str(&packet.payload);
+        } else if let Some(response) = pending.remove(&packet.request_id.wrapping_sub(1)) {
+            // the terminator of a command we're waiting on
+            let _ = response.reply.send(Ok(response.output));
+        } else if !packet.payload.is_empty() {
+            debug!(
+                request_id = packet.request_id,
+                payload = %packet.payload,
+                "untracked rcon response"
+            );
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RconConnection {
     writer: BufWriter<OwnedWriteHalf>,
     _read_handle: AbortOnDropHandle<Result<()>>,
     request_id: i32,
+    pending: PendingResponses,
+    ack_receiver: UnboundedReceiver<i32>,
     read_death_receiver: oneshot::Receiver<()>,
 }
 
-impl RconClient {
+impl RconConnection {
     #[inline]
-    pub async fn new(host: &str, pass: &str) -> Result<Self> {
+    async fn connect(host: &str, pass: &str) -> Result<Self> {
         let stream = TcpStream::connect(host).await?;
         stream.set_nodelay(true)?;
         let (reader, writer) = stream.into_split();
@@ -95,7 +166,7 @@ impl RconClient {
         Packet {
             request_id: 0,
             request_type: LOGIN,
-            payload: Cow::Borrowed(&pass),
+            payload: Cow::Borrowed(pass),
         }
         .write(&mut writer)
         .await?;
@@ -108,50 +179,503 @@ impl RconClient {
 
         info!("logged into rcon");
 
+        let pending = PendingResponses::default();
+        let (ack_sender, ack_receiver) = unbounded_channel();
         let (read_death_sender, read_death_receiver) = oneshot::channel();
 
         Ok(Self {
             writer,
-            _read_handle: AbortOnDropHandle::new(spawn(async move {
-                copy(&mut reader, &mut tokio::io::sink()).await?;
-                let _ = read_death_sender.send(());
-                Ok(())
+            _read_handle: AbortOnDropHandle::new(spawn({
+                let pending = pending.clone();
+                async move {
+                    let res = read_responses(reader, pending, ack_sender).await;
+                    let _ = read_death_sender.send(());
+                    res
+                }
             })),
             request_id: 1,
+            pending,
+            ack_receiver,
             read_death_receiver,
         })
     }
 
+    /// Reserves two IDs: one for the command, and the one after it for its
+    /// terminator, so the reader can find the command a terminator belongs to.
+    #[inline]
+    fn reserve_request_ids(&mut self) -> i32 {
+        // never hand out -1, which is what failed logins are answered with
+        if self.request_id > i32::MAX - 2 {
+            self.request_id = 1;
+        }
+
+        let request_id = self.request_id;
+        self.request_id += 2;
+        request_id
+    }
+
+    /// Writes a packet, then waits for the server to answer it. The vanilla
+    /// server reads each packet with a single read and drops the connection
+    /// if there's anything after it, so it can only ever have one packet from
+    /// us waiting to be read.
+    #[inline]
+    async fn send_packet(&mut self, packet: Packet<'_>) -> Result<()> {
+        packet.write(&mut self.writer).await?;
+        self.writer.flush().await?;
+
+        timeout(ACK_TIMEOUT, async {
+            loop {
+                match self.ack_receiver.recv().await {
+                    Some(request_id) if request_id == packet.request_id => return Ok(()),
+                    Some(_) => {}
+                    None => bail!("rcon read died"),
+                }
+            }
+        })
+        .await?
+    }
+
+    #[inline]
+    async fn send(&mut self, request: RconRequest) -> Result<()> {
+        let request_id = self.reserve_request_ids();
+        let tracked = request.reply.is_some();
+
+        // registered before anything is written so the reader can't beat us
+        if let Some(reply) = request.reply {
+            self.pending.lock().unwrap().insert(
+                request_id,
+                PendingResponse {
+                    output: String::new(),
+                    reply,
+                },
+            );
+        }
+
+        self.send_packet(Packet {
+            request_id,
+            request_type: COMMAND,
+            payload: Cow::Borrowed(&request.command),
+        })
+        .await?;
+
+        // sent once the first packet of the response is in, and since the
+        // server doesn't read it until it's done writing the response, it's
+        // answered after the rest of the response
+        if tracked {
+            self.send_packet(Packet {
+                request_id: request_id + 1,
+                request_type: RESPONSE_VALUE,
+                payload: Cow::Borrowed(""),
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A cheap handle for running commands over RCON. Commands are queued while
+/// the connection is down and sent once it's back.
+#[derive(Debug, Clone)]
+pub struct RconClient {
+    request_sender: UnboundedSender<RconRequest>,
+}
+
+impl RconClient {
+    #[inline]
+    pub fn new(
+        host: String,
+        pass: String,
+        game_event_sender: UnboundedSender<GameEvent>,
+    ) -> (Self, RconWorker) {
+        let (request_sender, request_receiver) = unbounded_channel();
+
+        (
+            Self { request_sender },
+            RconWorker {
+                host,
+                pass,
+                game_event_sender,
+                backlog: VecDeque::new(),
+                request_receiver,
+            },
+        )
+    }
+
+    #[inline]
+    pub async fn execute(&self, command: impl Into<String>) -> Result<String> {
+        let command = command.into();
+
+        // the server would hang up on us rather than refuse it
+        if command.len() > MAX_COMMAND_LENGTH {
+            bail!("command is {} bytes, over the rcon limit", command.len());
+        }
+
+        let (reply, response) = oneshot::channel();
+
+        self.request_sender.send(RconRequest {
+            command,
+            reply: Some(reply),
+        })?;
+
+        match timeout(EXECUTE_TIMEOUT, response).await {
+            Ok(Ok(output)) => output,
+            Ok(Err(_)) => bail!("rcon connection lost before the command finished"),
+            Err(_) => bail!("timed out waiting for rcon"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RconWorker {
+    host: String,
+    pass: String,
+    game_event_sender: UnboundedSender<GameEvent>,
+    backlog: VecDeque<RconRequest>,
+    request_receiver: UnboundedReceiver<RconRequest>,
+}
+
+impl RconWorker {
+    #[inline]
+    fn buffer(&mut self, request: RconRequest) {
+        if self.backlog.len() == MAX_BACKLOG {
+            self.backlog.pop_front();
+        }
+
+        self.backlog.push_back(request);
+    }
+
+    #[inline]
+    async fn next_request(
+        &mut self,
+        discord_message_receiver: &mut UnboundedReceiver<IncomingDiscordMessage>,
+        tellraw: &Tellraw,
+    ) -> Result<RconRequest> {
+        select! {
+            msg = discord_message_receiver.recv() => {
+                let Some(msg) = msg else {
+                    bail!("discord died")
+                };
+
+                Ok(RconRequest {
+                    command: msg.create_command(tellraw),
+                    reply: None,
+                })
+            }
+            Some(request) = self.request_receiver.recv() => Ok(request),
+        }
+    }
+
+    /// Buffers requests until `deadline`, so they aren't lost while we wait to
+    /// reconnect.
+    #[inline]
+    async fn buffer_until(
+        &mut self,
+        deadline: Instant,
+        discord_message_receiver: &mut UnboundedReceiver<IncomingDiscordMessage>,
+        tellraw: &Tellraw,
+    ) -> Result<()> {
+        loop {
+            select! {
+                _ = sleep_until(deadline) => return Ok(()),
+                request = self.next_request(discord_message_receiver, tellraw) => {
+                    self.buffer(request?);
+                }
+            }
+        }
+    }
+
+    #[inline]
+    async fn connect(
+        &mut self,
+        discord_message_receiver: &mut UnboundedReceiver<IncomingDiscordMessage>,
+        tellraw: &Tellraw,
+    ) -> Result<RconConnection> {
+        let mut delay = INITIAL_RECONNECT_DELAY;
+
+        loop {
+            match RconConnection::connect(&self.host, &self.pass).await {
+                Ok(connection) => return Ok(connection),
+                Err(e) => warn!(?e, ?delay, "failed to connect to rcon, retrying"),
+            }
+
+            self.buffer_until(Instant::now() + delay, discord_message_receiver, tellraw)
+                .await?;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+
+    /// Sends everything that piled up while we were disconnected.
+    #[inline]
+    async fn flush_backlog(&mut self, connection: &mut RconConnection) -> Result<()> {
+        while let Some(request) = self.backlog.pop_front() {
+            if request.is_abandoned() {
+                continue;
+            }
+
+            // whoever's waiting on a tracked command is told it failed, so only
+            // fire and forget commands are worth retrying
+            let retry = request.reply.is_none().then(|| request.command.clone());
+
+            if let Err(e) = connection.send(request).await {
+                if let Some(command) = retry {
+                    self.backlog.push_front(RconRequest {
+                        command,
+                        reply: None,
+                    });
+                }
+
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    async fn relay(
+        &mut self,
+        connection: &mut RconConnection,
+        discord_message_receiver: &mut UnboundedReceiver<IncomingDiscordMessage>,
+        tellraw: &Tellraw,
+    ) -> Result<()> {
+        self.flush_backlog(connection).await?;
+
+        loop {
+            let request = select! {
+                request = self.next_request(discord_message_receiver, tellraw) => request,
+                _ = &mut connection.read_death_receiver => bail!("rcon read died"),
+            };
+
+            let Ok(request) = request else {
+                // not a connection problem, so don't go on to reconnect
+                return Ok(());
+            };
+
+            self.buffer(request);
+            self.flush_backlog(connection).await?;
+        }
+    }
+
     #[inline]
     pub async fn handle(
         mut self,
         mut discord_message_receiver: UnboundedReceiver<IncomingDiscordMessage>,
-        tellraw_prefix: String,
+        tellraw: Tellraw,
     ) -> Result<()> {
+        let mut connected_before = false;
+
         loop {
-            let msg = select! {
-                msg = discord_message_receiver.recv() => {
-                    msg
-                },
-                _ = &mut self.read_death_receiver => {
-                    None
+            let mut connection = self
+                .connect(&mut discord_message_receiver, &tellraw)
+                .await?;
+
+            let _ = self.game_event_sender.send(if connected_before {
+                GameEvent::RconReconnected
+            } else {
+                GameEvent::RconConnected
+            });
+            connected_before = true;
+
+            match self
+                .relay(&mut connection, &mut discord_message_receiver, &tellraw)
+                .await
+            {
+                Ok(()) => bail!("discord died"),
+                Err(e) => {
+                    warn!(?e, "lost rcon connection, reconnecting");
+                    let _ = self.game_event_sender.send(GameEvent::RconDisconnected);
                 }
-            };
+            }
+        }
+    }
+}
 
-            let Some(msg) = msg else {
-                bail!("discord died or rcon read died")
-            };
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use tokio::net::TcpListener;
+    use twilight_model::id::Id;
+
+    use super::*;
+    use crate::component::EventFormat;
+
+    /// The most the vanilla server reads for a single packet.
+    const VANILLA_READ_SIZE: usize = 1460;
+
+    /// Stands in for a vanilla server, which reads each packet with a single
+    /// read and hangs up on anything it can't make sense of, like two
+    /// packets arriving together. Returns its address, along with whether it
+    /// had to hang up.
+    async fn serve_rcon() -> (String, Arc<AtomicBool>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let rejected = Arc::new(AtomicBool::new(false));
 
-            Packet {
-                request_id: self.request_id,
-                request_type: COMMAND,
-                payload: Cow::Borrowed(&msg.create_command(&tellraw_prefix)),
+        tokio::spawn({
+            let rejected = rejected.clone();
+
+            async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buffer = [0; VANILLA_READ_SIZE];
+
+                loop {
+                    let read = stream.read(&mut buffer).await.unwrap();
+
+                    if read == 0 {
+                        return;
+                    }
+
+                    let length = i32::from_le_bytes(buffer[..4].try_into().unwrap());
+
+                    if read < 14 || usize::try_from(length).unwrap() != read - 4 {
+                        rejected.store(true, Ordering::SeqCst);
+                        return;
+                    }
+
+                    let request_id = i32::from_le_bytes(buffer[4..8].try_into().unwrap());
+                    let request_type = i32::from_le_bytes(buffer[8..12].try_into().unwrap());
+                    let payload = String::from_utf8_lossy(&buffer[12..read - 2]);
+
+                    let responses = match (request_type, payload.as_ref()) {
+                        (LOGIN, "hunter2") => vec![String::new()],
+                        (LOGIN, _) => {
+                            let mut response = Vec::new();
+                            Packet {
+                                request_id: -1,
+                                request_type: COMMAND,
+                                payload: Cow::Borrowed(""),
+                            }
+                            .write(&mut response)
+                            .await
+                            .unwrap();
+                            stream.write_all(&response).await.unwrap();
+                            continue;
+                        }
+                        (COMMAND, "long") => {
+                            vec!["a".repeat(4096), "b".repeat(4096), "c".repeat(10)]
+                        }
+                        (COMMAND, command) => vec![format!("ran {command}")],
+                        (other, _) => vec![format!("Unknown request {other:x}")],
+                    };
+
+                    for payload in responses {
+                        let mut response = Vec::new();
+                        Packet {
+                            request_id,
+                            request_type: COMMAND,
+                            payload: Cow::Owned(payload),
+                        }
+                        .write(&mut response)
+                        .await
+                        .unwrap();
+                        stream.write_all(&response).await.unwrap();
+                    }
+                }
             }
-            .write(&mut self.writer)
-            .await?;
+        });
 
-            self.writer.flush().await?;
-            self.request_id += 1;
+        (address, rejected)
+    }
+
+    fn start(address: String, pass: &str) -> (RconClient, UnboundedSender<IncomingDiscordMessage>) {
+        let (game_event_sender, _) = unbounded_channel();
+        let (client, worker) = RconClient::new(address, pass.to_string(), game_event_sender);
+        let (discord_message_sender, discord_message_receiver) = unbounded_channel();
+
+        let tellraw = Tellraw {
+            prefix: "tellraw @a".to_string(),
+            format: EventFormat::Modern,
+            max_length: Some(MAX_COMMAND_LENGTH),
+        };
+
+        tokio::spawn(worker.handle(discord_message_receiver, tellraw));
+        (client, discord_message_sender)
+    }
+
+    #[tokio::test]
+    async fn never_sends_packets_together() {
+        let (address, rejected) = serve_rcon().await;
+        let (client, discord_message_sender) = start(address, "hunter2");
+
+        for content in ["hi", "there"] {
+            discord_message_sender
+                .send(IncomingDiscordMessage {
+                    username: "bob".to_string(),
+                    content: content.to_string(),
+                    name: "bob".to_string(),
+                    discriminator: 0,
+                    user_id: Id::new(1),
+                    top_role: None,
+                    color: None,
+                })
+                .unwrap();
         }
+
+        let (list, tps, say) = tokio::join!(
+            client.execute("list"),
+            client.execute("tps"),
+            client.execute("say hello"),
+        );
+
+        assert_eq!(list.unwrap(), "ran list");
+        assert_eq!(tps.unwrap(), "ran tps");
+        assert_eq!(say.unwrap(), "ran say hello");
+        assert!(!rejected.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn fits_long_messages_in_a_packet() {
+        let (address, rejected) = serve_rcon().await;
+        let (client, discord_message_sender) = start(address, "hunter2");
+
+        discord_message_sender
+            .send(IncomingDiscordMessage {
+                username: "bob".to_string(),
+                content: "*é* \"quoted\" ".repeat(200),
+                name: "bob".to_string(),
+                discriminator: 0,
+                user_id: Id::new(1),
+                top_role: None,
+                color: None,
+            })
+            .unwrap();
+
+        assert_eq!(client.execute("list").await.unwrap(), "ran list");
+        assert!(!rejected.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn refuses_commands_too_long_to_send() {
+        let (address, rejected) = serve_rcon().await;
+        let (client, _discord_message_sender) = start(address, "hunter2");
+
+        let command = format!("say {}", "a".repeat(MAX_COMMAND_LENGTH));
+        assert!(client.execute(command).await.is_err());
+
+        assert_eq!(client.execute("list").await.unwrap(), "ran list");
+        assert!(!rejected.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn collects_responses_split_over_packets() {
+        let (address, rejected) = serve_rcon().await;
+        let (client, _discord_message_sender) = start(address, "hunter2");
+
+        let output = client.execute("long").await.unwrap();
+        assert_eq!(
+            output,
+            format!("{}{}{}", "a".repeat(4096), "b".repeat(4096), "c".repeat(10))
+        );
+
+        assert_eq!(client.execute("list").await.unwrap(), "ran list");
+        assert!(!rejected.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_password() {
+        let (address, _) = serve_rcon().await;
+        assert!(RconConnection::connect(&address, "nope").await.is_err());
     }
 }
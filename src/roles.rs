@@ -0,0 +1,76 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use twilight_model::{
+    guild::Role,
+    id::{Id, marker::RoleMarker},
+};
+
+#[derive(Debug, Clone)]
+struct CachedRole {
+    name: String,
+    /// Zero if the role doesn't have one.
+    color: u32,
+    position: i64,
+}
+
+/// The roles of every guild we're in, kept up to date from the gateway so we
+/// don't have to ask Discord about them for every message.
+#[derive(Debug, Clone, Default)]
+pub struct RoleCache {
+    roles: Arc<Mutex<HashMap<Id<RoleMarker>, CachedRole>>>,
+}
+
+impl RoleCache {
+    #[inline]
+    pub fn insert(&self, role: &Role) {
+        self.roles.lock().unwrap().insert(
+            role.id,
+            CachedRole {
+                name: role.name.clone(),
+                color: role.color,
+                position: role.position,
+            },
+        );
+    }
+
+    #[inline]
+    pub fn remove(&self, role_id: Id<RoleMarker>) {
+        self.roles.lock().unwrap().remove(&role_id);
+    }
+
+    /// Whatever `map` gives for the highest of `roles` it gives anything for,
+    /// ordering them the same way Discord does on a profile.
+    #[inline]
+    fn highest<T>(
+        &self,
+        roles: &[Id<RoleMarker>],
+        map: impl Fn(&CachedRole) -> Option<T>,
+    ) -> Option<T> {
+        let cached = self.roles.lock().unwrap();
+
+        roles
+            .iter()
+            .filter_map(|id| cached.get(id).map(|role| (id, role)))
+            // Discord breaks ties in position with the older role
+            .filter_map(|(id, role)| Some(((role.position, Reverse(id.get())), map(role)?)))
+            .max_by_key(|(order, _)| *order)
+            .map(|(_, value)| value)
+    }
+
+    /// The name of the highest of `roles`, the one Discord lists first.
+    #[inline]
+    pub fn top_role(&self, roles: &[Id<RoleMarker>]) -> Option<String> {
+        self.highest(roles, |role| Some(role.name.clone()))
+    }
+
+    /// The color of the highest of `roles` that has one, which is the color
+    /// Discord shows their name in.
+    #[inline]
+    pub fn color(&self, roles: &[Id<RoleMarker>]) -> Option<u32> {
+        self.highest(roles, |role| (role.color != 0).then_some(role.color))
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::legacy::LegacyProfile;
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub name: String,
+    pub joined_at: Instant,
+}
+
+impl Session {
+    #[inline]
+    pub fn duration(&self) -> Duration {
+        self.joined_at.elapsed()
+    }
+}
+
+/// Who's on the server right now, going by the join and leave events it sends
+/// us. Cleared whenever the wrapped server goes down, since it won't tell us
+/// about everyone who got disconnected.
+#[derive(Debug, Clone, Default)]
+pub struct Roster {
+    sessions: Arc<Mutex<HashMap<Uuid, Session>>>,
+}
+
+impl Roster {
+    #[inline]
+    pub fn join(&self, profile: &LegacyProfile) {
+        self.sessions.lock().unwrap().insert(
+            profile.user_id,
+            Session {
+                name: profile.user_display_name.clone(),
+                joined_at: Instant::now(),
+            },
+        );
+    }
+
+    /// Returns the session that just ended, if we saw it start.
+    #[inline]
+    pub fn leave(&self, profile: &LegacyProfile) -> Option<Session> {
+        self.sessions.lock().unwrap().remove(&profile.user_id)
+    }
+
+    #[inline]
+    pub fn clear(&self) {
+        self.sessions.lock().unwrap().clear();
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    #[inline]
+    pub fn names(&self) -> Vec<String> {
+        let mut names = self
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .map(|session| session.name.clone())
+            .collect::<Vec<_>>();
+        names.sort_unstable_by_key(|name| name.to_lowercase());
+
+        names
+    }
+}
+
+/// Formats a session length or uptime the way we show it in Discord, i.e.
+/// `2h13m`.
+#[inline]
+pub fn format_duration(duration: Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+
+    match (minutes / 60, minutes % 60) {
+        (0, 0) => format!("{}s", duration.as_secs()),
+        (0, minutes) => format!("{minutes}m"),
+        (hours, minutes) => format!("{hours}h{minutes}m"),
+    }
+}
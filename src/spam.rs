@@ -0,0 +1,209 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio::{
+    spawn,
+    time::{Instant, sleep},
+};
+use uuid::Uuid;
+
+use crate::{AppState, discord::schedule_send_discord, legacy::LegacyProfile};
+
+/// How many held back messages we'll show at once when coalescing, so one
+/// relayed message can't get too long either.
+const MAX_COALESCED_LINES: usize = 10;
+/// How often moderators hear about a single player spamming in block mode.
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What happens to a message from a player who's sending too many.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SpamAction {
+    /// Relay everything, spam or not.
+    #[default]
+    Off,
+    /// Don't relay it to Discord. Players in game still see it.
+    Drop,
+    /// Hold it back and relay everything held back as one message once the
+    /// player slows down.
+    Coalesce,
+    /// Don't relay it, and have the server hide it from other players too.
+    Block,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SpamPolicy {
+    pub action: SpamAction,
+    /// How many messages a player can send in quick succession.
+    pub burst: u32,
+    /// How long it takes for a player to be allowed one more message.
+    pub refill: Duration,
+    /// How long a message counts as a duplicate of the one sent before it.
+    pub duplicate_window: Duration,
+}
+
+#[derive(Debug)]
+struct Sender {
+    tokens: f64,
+    refilled_at: Instant,
+    last_message: String,
+    last_message_at: Instant,
+    held_back: Vec<String>,
+    reported_at: Option<Instant>,
+    unreported: u32,
+}
+
+/// Keeps a token bucket for every player, so a single player spamming can't
+/// get our webhook rate limited for everyone else.
+#[derive(Debug, Clone)]
+pub struct SpamGuard {
+    policy: SpamPolicy,
+    senders: Arc<Mutex<HashMap<Uuid, Sender>>>,
+}
+
+impl SpamGuard {
+    #[inline]
+    pub fn new(policy: SpamPolicy) -> Self {
+        Self {
+            policy,
+            senders: Arc::default(),
+        }
+    }
+
+    #[inline]
+    pub const fn action(&self) -> SpamAction {
+        self.policy.action
+    }
+
+    /// Records a message, returning whether it's spam.
+    #[inline]
+    pub fn is_spam(&self, user_id: Uuid, text: &str) -> bool {
+        let now = Instant::now();
+        let burst = f64::from(self.policy.burst);
+        let mut senders = self.senders.lock().unwrap();
+
+        let sender = senders.entry(user_id).or_insert_with(|| Sender {
+            tokens: burst,
+            refilled_at: now,
+            last_message: String::new(),
+            last_message_at: now,
+            held_back: Vec::new(),
+            reported_at: None,
+            unreported: 0,
+        });
+
+        let refilled = now.duration_since(sender.refilled_at).as_secs_f64()
+            / self.policy.refill.as_secs_f64().max(f64::EPSILON);
+        sender.tokens = (sender.tokens + refilled).min(burst);
+        sender.refilled_at = now;
+
+        let text = text.trim();
+        let duplicate = sender.last_message.eq_ignore_ascii_case(text)
+            && now.duration_since(sender.last_message_at) < self.policy.duplicate_window;
+
+        sender.last_message.clear();
+        sender.last_message.push_str(text);
+        sender.last_message_at = now;
+
+        if duplicate || sender.tokens < 1.0 {
+            return true;
+        }
+
+        sender.tokens -= 1.0;
+        false
+    }
+
+    /// Whether moderators should hear about a player's latest blocked
+    /// message, returning how many went unreported since the last time they
+    /// did, so a flood of spam doesn't turn into a flood of reports.
+    #[inline]
+    pub fn take_report(&self, user_id: Uuid) -> Option<u32> {
+        let now = Instant::now();
+        let mut senders = self.senders.lock().unwrap();
+        let sender = senders.get_mut(&user_id)?;
+
+        if sender
+            .reported_at
+            .is_some_and(|reported_at| now.duration_since(reported_at) < REPORT_INTERVAL)
+        {
+            sender.unreported += 1;
+            return None;
+        }
+
+        sender.reported_at = Some(now);
+        Some(std::mem::take(&mut sender.unreported))
+    }
+
+    /// Stops tracking a player, unless they still have messages held back.
+    #[inline]
+    pub fn forget(&self, user_id: Uuid) {
+        let mut senders = self.senders.lock().unwrap();
+
+        if senders
+            .get(&user_id)
+            .is_some_and(|sender| sender.held_back.is_empty())
+        {
+            senders.remove(&user_id);
+        }
+    }
+}
+
+/// Holds a spammed message back to be relayed along with any others once the
+/// player has had time to calm down.
+#[inline]
+pub fn coalesce(state: &AppState, profile: &LegacyProfile, text: String) {
+    let guard = &state.spam_guard;
+
+    {
+        let mut senders = guard.senders.lock().unwrap();
+        let Some(sender) = senders.get_mut(&profile.user_id) else {
+            return;
+        };
+
+        sender.held_back.push(text);
+
+        // somebody's already waiting to relay these
+        if sender.held_back.len() > 1 {
+            return;
+        }
+    }
+
+    let state = state.clone();
+    let user_id = profile.user_id;
+    let name = profile.user_display_name.clone();
+
+    spawn(async move {
+        sleep(state.spam_guard.policy.refill).await;
+
+        let held_back = state
+            .spam_guard
+            .senders
+            .lock()
+            .unwrap()
+            .get_mut(&user_id)
+            .map(|sender| std::mem::take(&mut sender.held_back))
+            .unwrap_or_default();
+
+        if held_back.is_empty() {
+            return;
+        }
+
+        let mut content = held_back
+            .iter()
+            .take(MAX_COALESCED_LINES)
+            .cloned()
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        if held_back.len() > MAX_COALESCED_LINES {
+            let more = held_back.len() - MAX_COALESCED_LINES;
+            content.push_str(&format!("\n(and {more} more)"));
+        }
+
+        schedule_send_discord(&state, name.into(), Some(user_id), content);
+    });
+}
@@ -0,0 +1,88 @@
+use std::{
+    sync::LazyLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use regex::Regex;
+use tokio::time::{MissedTickBehavior, interval};
+use tracing::warn;
+use twilight_model::id::{Id, marker::ChannelMarker};
+
+use crate::{
+    AppState, console::Console, content::strip_minecraft_formatting, roster::format_duration,
+};
+
+/// Discord only lets bots edit a channel's name or topic twice every ten
+/// minutes.
+const MIN_TOPIC_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Paper's `tps` output, with a `*` in front of values capped at 20.
+static TPS_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"TPS from last 1m, 5m, 15m: \*?(?P<tps>[0-9.]+)").unwrap());
+
+/// Vanilla doesn't have a `tps` command, so this only works on servers that
+/// add one, and only over RCON, where asking doesn't clutter the server log.
+#[inline]
+async fn query_tps(state: &AppState) -> Option<String> {
+    let Console::Rcon(rcon_client) = &state.console else {
+        return None;
+    };
+
+    let output = rcon_client.execute("tps").await.ok()?;
+    let output = strip_minecraft_formatting(&output);
+
+    Some(TPS_REGEX.captures(&output)?["tps"].to_string())
+}
+
+#[inline]
+async fn describe_status(state: &AppState) -> String {
+    let presence = *state.presence.borrow();
+    let updated_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let Some(online_since) = presence.online_since else {
+        return format!("Server offline | updated <t:{updated_at}:R>");
+    };
+
+    let players = match presence.max {
+        Some(max) => format!("{}/{max} players", presence.players),
+        None => format!("{} online", presence.players),
+    };
+
+    let mut parts = vec![
+        "Server online".to_string(),
+        players,
+        format!("up {}", format_duration(online_since.elapsed())),
+    ];
+
+    if let Some(tps) = query_tps(state).await {
+        parts.push(format!("{tps} TPS"));
+    }
+
+    parts.push(format!("updated <t:{updated_at}:R>"));
+    parts.join(" | ")
+}
+
+/// Keeps the bridge channel's topic up to date with the server's status.
+#[inline]
+pub async fn update_topic(
+    state: AppState,
+    channel_id: Id<ChannelMarker>,
+    period: Duration,
+) -> Result<()> {
+    let mut ticker = interval(period.max(MIN_TOPIC_INTERVAL));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+
+        let topic = describe_status(&state).await;
+
+        if let Err(e) = state.client.update_channel(channel_id).topic(&topic).await {
+            warn!(?e, "failed to update channel topic");
+        }
+    }
+}
@@ -1,37 +1,59 @@
 use anyhow::{Result, anyhow};
 use nix::{
-    sys::signal::{Signal::SIGINT, kill},
+    sys::signal::{Signal::SIGTERM, kill},
     unistd::Pid,
 };
+use serde::Deserialize;
 use std::{
     collections::VecDeque,
     convert::Infallible,
     env::args,
     io::{BufRead, stdin},
-    process::Stdio,
+    process::{ExitStatus, Stdio},
+    time::Duration,
 };
-use tracing::info;
+use tracing::{error, info, warn};
 
 use tokio::{
-    io::AsyncWriteExt,
-    process::{ChildStdin, Command},
-    select,
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    process::{Child, ChildStdin, Command},
+    select, spawn,
     sync::{
+        broadcast,
         mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
         oneshot::Receiver,
     },
+    task::JoinSet,
+    time::{Instant, sleep, timeout},
 };
 
-use crate::discord::IncomingDiscordMessage;
+use crate::{
+    component::Tellraw,
+    console::{ConsoleCommand, collect_output},
+    discord::{IncomingDiscordMessage, create_announcement},
+    events::GameEvent,
+};
+
+/// Points during the shutdown countdown at which players are reminded.
+const COUNTDOWN_STEPS: &[u64] = &[300, 120, 60, 30, 10, 5, 4, 3, 2, 1];
+const TERMINATE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many lines of output a slow command output collector can fall behind
+/// by before it starts missing some.
+const OUTPUT_CAPACITY: usize = 256;
 
 #[inline]
-fn read_stdin(sender: UnboundedSender<String>) -> Result<Infallible> {
+fn read_stdin(sender: UnboundedSender<String>) -> Result<()> {
     let mut buffer = String::new();
     let mut stdin_lock = stdin().lock();
 
     loop {
         buffer.clear();
-        stdin_lock.read_line(&mut buffer)?;
+
+        // stdin is usually /dev/null when we're run as a service
+        if stdin_lock.read_line(&mut buffer)? == 0 {
+            return Ok(());
+        }
+
         sender.send(buffer.clone())?;
     }
 }
@@ -43,88 +65,384 @@ enum StdinMessage {
 
 impl StdinMessage {
     #[inline]
-    fn as_string(self, tellraw_prefix: &str) -> String {
+    fn into_string(self, tellraw: &Tellraw) -> String {
         match self {
             Self::DiscordMessage(incoming_discord_message) => {
-                incoming_discord_message.create_command(tellraw_prefix)
+                incoming_discord_message.create_command(tellraw)
             }
             Self::UserInput(input) => input,
         }
     }
 
     #[inline]
-    async fn write(self, tellraw_prefix: &str, to: &mut ChildStdin) -> Result<()> {
-        let as_string = self.as_string(tellraw_prefix);
-
-        to.write_all(as_string.as_bytes()).await?;
-        to.write_u8(b'\n').await?;
-        to.flush().await?;
-        Ok(())
+    async fn write(self, tellraw: &Tellraw, to: &mut ChildStdin) -> Result<()> {
+        write_line(to, &self.into_string(tellraw)).await
     }
 }
 
 #[inline]
-async fn pipe_stdin(
-    mut stdin: ChildStdin,
-    mut stdin_receiver: UnboundedReceiver<String>,
-    mut discord_message_receiver: UnboundedReceiver<IncomingDiscordMessage>,
-    tellraw_prefix: String,
-) -> Result<Infallible> {
+async fn write_line(to: &mut ChildStdin, line: &str) -> Result<()> {
+    to.write_all(line.as_bytes()).await?;
+    to.write_u8(b'\n').await?;
+    to.flush().await?;
+    Ok(())
+}
+
+/// Copies the server's output to ours, picking events out of it on the way.
+/// This has to keep reading until the server closes the pipe, whatever goes
+/// wrong, or the server will block once the pipe fills up.
+#[inline]
+async fn pipe_output<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    from: R,
+    mut to: W,
+    game_event_sender: UnboundedSender<GameEvent>,
+    output_sender: broadcast::Sender<String>,
+) {
+    let mut from = BufReader::new(from);
+    let mut buffer = Vec::new();
+    let mut writable = true;
+
     loop {
-        let msg = select! {
-            line = stdin_receiver.recv() => {
-                StdinMessage::UserInput(line.ok_or_else(|| anyhow!("stdin sender dropped"))?)
+        buffer.clear();
+
+        match from.read_until(b'\n', &mut buffer).await {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(e) => {
+                warn!(?e, "failed to read server output");
+                return;
+            }
+        }
+
+        if buffer.ends_with(b"\n") {
+            buffer.pop();
+
+            if buffer.ends_with(b"\r") {
+                buffer.pop();
+            }
+        }
+
+        let line = String::from_utf8_lossy(&buffer).into_owned();
+
+        // whoever reads our output going away shouldn't stop the server
+        if writable && let Err(e) = write_output(&mut to, &line).await {
+            warn!(?e, "failed to copy server output, no longer copying it");
+            writable = false;
+        }
+
+        if let Some(event) = GameEvent::parse(&line) {
+            let _ = game_event_sender.send(event);
+        }
+
+        // only fails when nobody's waiting on command output
+        let _ = output_sender.send(line);
+    }
+}
+
+#[inline]
+async fn write_output<W: AsyncWrite + Unpin>(to: &mut W, line: &str) -> std::io::Result<()> {
+    to.write_all(line.as_bytes()).await?;
+    to.write_u8(b'\n').await?;
+    to.flush().await
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartMode {
+    Always,
+    OnCrash,
+    #[default]
+    Never,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RestartPolicy {
+    pub mode: RestartMode,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// How many restarts we allow within `window` before assuming the server
+    /// is crash looping and giving up.
+    pub max_restarts: usize,
+    pub window: Duration,
+}
+
+impl RestartPolicy {
+    #[inline]
+    fn should_restart(&self, status: ExitStatus) -> bool {
+        match self.mode {
+            RestartMode::Always => true,
+            RestartMode::OnCrash => !status.success(),
+            RestartMode::Never => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ShutdownPolicy {
+    /// How long the server gets to save and exit after `stop` before we start
+    /// sending signals.
+    pub timeout: Duration,
+    /// How long players get warned for in-game before we send `stop`.
+    pub countdown: Duration,
+}
+
+enum ServerOutcome {
+    Exited(ExitStatus),
+    Stopped,
+}
+
+struct Wrapper {
+    program: String,
+    args: Vec<String>,
+    stdin_receiver: UnboundedReceiver<String>,
+    discord_message_receiver: UnboundedReceiver<IncomingDiscordMessage>,
+    command_receiver: UnboundedReceiver<ConsoleCommand>,
+    output_sender: broadcast::Sender<String>,
+    game_event_sender: UnboundedSender<GameEvent>,
+    tellraw: Tellraw,
+    shutdown_policy: ShutdownPolicy,
+}
+
+impl Wrapper {
+    #[inline]
+    async fn pipe_stdin(&mut self, stdin: &mut ChildStdin) -> Result<Infallible> {
+        loop {
+            let msg = select! {
+                Some(line) = self.stdin_receiver.recv() => {
+                    StdinMessage::UserInput(line)
+                }
+                discord_message = self.discord_message_receiver.recv() => {
+                    StdinMessage::DiscordMessage(discord_message.ok_or_else(|| anyhow!("discord message sender dropped"))?)
+                }
+                Some(command) = self.command_receiver.recv() => {
+                    // these pile up while the server is restarting
+                    if command.is_abandoned() {
+                        continue;
+                    }
+
+                    // subscribed before the command is written so none of its
+                    // output is missed
+                    let output_receiver = self.output_sender.subscribe();
+                    spawn(async move {
+                        let _ = command.reply.send(collect_output(output_receiver).await);
+                    });
+
+                    StdinMessage::UserInput(command.command)
+                }
+            };
+
+            msg.write(&self.tellraw, stdin).await?;
+        }
+    }
+    #[inline]
+    async fn announce_shutdown(&self, stdin: &mut ChildStdin) -> Result<()> {
+        let mut remaining = self.shutdown_policy.countdown.as_secs();
+
+        while remaining > 0 {
+            let announcement =
+                create_announcement(&self.tellraw, &format!("Server stopping in {remaining}s"));
+            write_line(stdin, &announcement).await?;
+
+            let next = COUNTDOWN_STEPS
+                .iter()
+                .copied()
+                .find(|&step| step < remaining)
+                .unwrap_or(0);
+
+            sleep(Duration::from_secs(remaining - next)).await;
+            remaining = next;
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    async fn shutdown(&self, child: &mut Child, stdin: &mut ChildStdin) -> Result<ExitStatus> {
+        info!("gracefully shutting down server");
+        let _ = self.game_event_sender.send(GameEvent::ServerStopping);
+
+        select! {
+            status = child.wait() => return Ok(status?),
+            res = self.announce_shutdown(stdin) => {
+                if let Err(e) = res {
+                    warn!(?e, "failed to announce shutdown in-game");
+                }
             }
-            discord_message = discord_message_receiver.recv() => {
-                StdinMessage::DiscordMessage(discord_message.ok_or_else(|| anyhow!("discord message sender dropped"))?)
+        }
+
+        if let Err(e) = write_line(stdin, "stop").await {
+            warn!(?e, "failed to send stop command to server");
+        }
+
+        if let Ok(status) = timeout(self.shutdown_policy.timeout, child.wait()).await {
+            return Ok(status?);
+        }
+
+        if let Some(pid) = child.id() {
+            warn!("server didn't stop in time, sending SIGTERM");
+            kill(Pid::from_raw(pid.cast_signed()), SIGTERM)?;
+
+            if let Ok(status) = timeout(TERMINATE_TIMEOUT, child.wait()).await {
+                return Ok(status?);
             }
+        }
+
+        warn!("server didn't terminate in time, killing it");
+        child.kill().await?;
+        Ok(child.wait().await?)
+    }
+
+    #[inline]
+    async fn run_server(&mut self, death_receiver: &mut Receiver<()>) -> Result<ServerOutcome> {
+        let mut command = Command::new(&self.program);
+
+        command
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        info!("starting server");
+        let _ = self.game_event_sender.send(GameEvent::ServerLaunching);
+
+        let mut child = command.spawn()?;
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow!("child does not have stdin"))?;
+        let mut output_pipes = JoinSet::new();
+
+        if let Some(stdout) = child.stdout.take() {
+            output_pipes.spawn(pipe_output(
+                stdout,
+                tokio::io::stdout(),
+                self.game_event_sender.clone(),
+                self.output_sender.clone(),
+            ));
+        }
+
+        if let Some(stderr) = child.stderr.take() {
+            output_pipes.spawn(pipe_output(
+                stderr,
+                tokio::io::stderr(),
+                self.game_event_sender.clone(),
+                self.output_sender.clone(),
+            ));
+        }
+
+        let status = select! {
+            status = child.wait() => Some(status?),
+            res = self.pipe_stdin(&mut stdin) => {
+                let Err(e) = res;
+                warn!(?e, "failed to write to server stdin");
+
+                // most likely the server is on its way out, so find out how
+                select! {
+                    status = child.wait() => Some(status?),
+                    _ = &mut *death_receiver => None,
+                }
+            }
+            _ = &mut *death_receiver => None,
         };
 
-        msg.write(&tellraw_prefix, &mut stdin).await?;
+        let (status, outcome) = match status {
+            Some(status) => (status, ServerOutcome::Exited(status)),
+            None => (
+                self.shutdown(&mut child, &mut stdin).await?,
+                ServerOutcome::Stopped,
+            ),
+        };
+
+        // let the output drain first so the exit is announced after anything
+        // the server logged on its way down
+        output_pipes.join_all().await;
+        info!(%status, "server exited");
+        let _ = self
+            .game_event_sender
+            .send(GameEvent::ServerExited { status });
+
+        Ok(outcome)
     }
 }
 
 #[inline]
 pub async fn launch_wrapper(
     discord_message_receiver: UnboundedReceiver<IncomingDiscordMessage>,
-    tellraw_prefix: String,
-    death_receiver: Receiver<()>,
+    command_receiver: UnboundedReceiver<ConsoleCommand>,
+    game_event_sender: UnboundedSender<GameEvent>,
+    tellraw: Tellraw,
+    restart_policy: RestartPolicy,
+    shutdown_policy: ShutdownPolicy,
+    mut death_receiver: Receiver<()>,
 ) -> Result<()> {
-    let mut args: VecDeque<String> = args().into_iter().skip(1).collect();
-    let mut command = Command::new(
-        args.pop_front()
-            .ok_or_else(|| anyhow!("expected first arg to be java path"))?,
-    );
+    let mut args: VecDeque<String> = args().skip(1).collect();
+    let program = args
+        .pop_front()
+        .ok_or_else(|| anyhow!("expected first arg to be java path"))?;
 
     let (stdin_sender, stdin_receiver) = unbounded_channel();
     std::thread::spawn(|| read_stdin(stdin_sender));
 
-    command.args(args.into_iter()).stdin(Stdio::piped());
-
-    info!("starting server");
-
-    let mut child = command.spawn()?;
-    let stdin = child.stdin.take();
-    select! {
-        _ = child.wait() => {},
-        _ = pipe_stdin(
-            stdin.ok_or_else(|| anyhow!("child does not have stdin"))?,
-            stdin_receiver,
-            discord_message_receiver,
-            tellraw_prefix
-        ) => {}
-        _ = death_receiver => {}
+    let mut wrapper = Wrapper {
+        program,
+        args: args.into(),
+        stdin_receiver,
+        discord_message_receiver,
+        command_receiver,
+        output_sender: broadcast::channel(OUTPUT_CAPACITY).0,
+        game_event_sender,
+        tellraw,
+        shutdown_policy,
     };
 
-    let Some(pid) = child.id() else {
-        return Ok(());
-    };
+    let mut restarts: VecDeque<Instant> = VecDeque::new();
+    let mut delay = restart_policy.initial_delay;
+
+    loop {
+        let started_at = Instant::now();
 
-    let pid = Pid::from_raw(pid.cast_signed());
+        let ServerOutcome::Exited(status) = wrapper.run_server(&mut death_receiver).await? else {
+            return Ok(());
+        };
 
-    info!("gracefully shutting down server");
-    kill(pid, SIGINT)?;
-    info!("server gracefully shutdown");
+        if !restart_policy.should_restart(status) {
+            return Ok(());
+        }
 
-    Ok(())
+        // a server that stayed up for a while isn't crash looping
+        if started_at.elapsed() >= restart_policy.window {
+            delay = restart_policy.initial_delay;
+        }
+
+        while restarts
+            .front()
+            .is_some_and(|restart| restart.elapsed() >= restart_policy.window)
+        {
+            restarts.pop_front();
+        }
+
+        if restarts.len() >= restart_policy.max_restarts {
+            error!(
+                restarts = restarts.len(),
+                "server keeps crashing, giving up on restarting it"
+            );
+            let _ = wrapper
+                .game_event_sender
+                .send(GameEvent::ServerRestartsExhausted);
+            return Ok(());
+        }
+
+        info!(?delay, "restarting server");
+        let _ = wrapper
+            .game_event_sender
+            .send(GameEvent::ServerRestartScheduled { delay });
+
+        select! {
+            _ = sleep(delay) => {}
+            _ = &mut death_receiver => return Ok(()),
+        }
+
+        restarts.push_back(Instant::now());
+        delay = (delay * 2).min(restart_policy.max_delay);
+    }
 }